@@ -1,8 +1,104 @@
-use nalgebra::{Matrix4, Perspective3};
+use std::any::Any;
+
+use nalgebra::{Matrix4, Orthographic3, Perspective3, Point2, Point3, Unit, Vector3, Vector4};
+use serde::{Deserialize, Serialize};
 
 use crate::transform::TransformComponent;
 
-pub trait CameraMatrix: Send + Sync {
+/// Gives access to [`Any`] from trait objects
+pub trait AsAny: Any {
+    fn as_any(&self) -> &dyn Any;
+    fn as_any_mut(&mut self) -> &mut dyn Any;
+}
+impl<T: Any> AsAny for T {
+    fn as_any(&self) -> &dyn Any { self }
+    fn as_any_mut(&mut self) -> &mut dyn Any { self }
+}
+
+/// Range of the clip space depth of a projection, between the near and the far plane
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum ClipDepth {
+    /// OpenGL's convention, used by nalgebra's projections
+    NegativeOneToOne,
+    ZeroToOne,
+    /// Reverse-Z, the near plane is at 1 and the far plane at 0 which spreads the precision of
+    /// floating point depth buffers evenly
+    OneToZero,
+}
+impl ClipDepth {
+    pub fn near(self) -> f32 {
+        match self {
+            ClipDepth::NegativeOneToOne => -1.,
+            ClipDepth::ZeroToOne => 0.,
+            ClipDepth::OneToZero => 1.,
+        }
+    }
+    pub fn far(self) -> f32 {
+        match self {
+            ClipDepth::NegativeOneToOne | ClipDepth::ZeroToOne => 1.,
+            ClipDepth::OneToZero => 0.,
+        }
+    }
+    /// Whether a depth is closer to the camera than another one
+    pub fn is_closer(self, depth: f32, other: f32) -> bool {
+        match self {
+            ClipDepth::OneToZero => depth > other,
+            _ => depth < other,
+        }
+    }
+    /// Depth test keeping the closest fragments
+    pub fn depth_compare(self) -> wgpu::CompareFunction {
+        match self {
+            ClipDepth::OneToZero => wgpu::CompareFunction::Greater,
+            _ => wgpu::CompareFunction::Less,
+        }
+    }
+
+    /// Matrix remapping the clip space depth of a projection from this convention to another one
+    pub fn conversion_to(self, target: ClipDepth) -> Matrix4<f32> {
+        // Every convention goes through [0, 1]
+        #[rustfmt::skip]
+        let to_zero_to_one = match self {
+            ClipDepth::NegativeOneToOne => Matrix4::new(
+                1., 0., 0.,  0.,
+                0., 1., 0.,  0.,
+                0., 0., 0.5, 0.5,
+                0., 0., 0.,  1.,
+            ),
+            ClipDepth::ZeroToOne => Matrix4::identity(),
+            ClipDepth::OneToZero => Matrix4::new(
+                1., 0., 0.,  0.,
+                0., 1., 0.,  0.,
+                0., 0., -1., 1.,
+                0., 0., 0.,  1.,
+            ),
+        };
+        #[rustfmt::skip]
+        let from_zero_to_one = match target {
+            ClipDepth::NegativeOneToOne => Matrix4::new(
+                1., 0., 0.,  0.,
+                0., 1., 0.,  0.,
+                0., 0., 2.,  -1.,
+                0., 0., 0.,  1.,
+            ),
+            ClipDepth::ZeroToOne => Matrix4::identity(),
+            ClipDepth::OneToZero => Matrix4::new(
+                1., 0., 0.,  0.,
+                0., 1., 0.,  0.,
+                0., 0., -1., 1.,
+                0., 0., 0.,  1.,
+            ),
+        };
+        from_zero_to_one * to_zero_to_one
+    }
+}
+
+pub trait CameraMatrix: AsAny + Send + Sync {
+    fn clip_depth(&self) -> ClipDepth { ClipDepth::NegativeOneToOne }
+    /// Called by [`Renderer::resize`](crate::renderer::Renderer::resize) with the new aspect ratio
+    /// (width / height) of the window
+    fn set_aspect(&mut self, _aspect: f32) {}
+
     fn get_view_matrix(&self, transform: &TransformComponent) -> Matrix4<f32> {
         transform.to_homogeneous().try_inverse().unwrap()
     }
@@ -10,19 +106,398 @@ pub trait CameraMatrix: Send + Sync {
     fn get_vp_matrix(&self, transform: &TransformComponent) -> Matrix4<f32> {
         self.get_projection_matrix() * self.get_view_matrix(transform)
     }
+
+    /// Distance along the view direction of a point whose depth buffer value is `depth`, the
+    /// depth buffer using the `buffer_depth` convention
+    ///
+    /// Infinite for the far plane of infinite projections.
+    fn linear_depth(&self, depth: f32, buffer_depth: ClipDepth) -> f32 {
+        let projection =
+            self.clip_depth().conversion_to(buffer_depth) * self.get_projection_matrix();
+        let point = projection.try_inverse().unwrap() * Vector4::new(0., 0., depth, 1.);
+        -point.z / point.w
+    }
+    /// World space ray going through a point of the screen given in normalized device
+    /// coordinates, starting on the near plane
+    fn screen_ray(
+        &self, transform: &TransformComponent, ndc: &Point2<f32>,
+    ) -> (Point3<f32>, Unit<Vector3<f32>>) {
+        let inverse = self.get_vp_matrix(transform).try_inverse().unwrap();
+        let clip_depth = self.clip_depth();
+        // The far plane can be at infinity, a point half way in clip space is always finite
+        let middle = (clip_depth.near() + clip_depth.far()) / 2.;
+        let near = inverse.transform_point(&Point3::new(ndc.x, ndc.y, clip_depth.near()));
+        let further = inverse.transform_point(&Point3::new(ndc.x, ndc.y, middle));
+        (near, Unit::new_normalize(further - near))
+    }
 }
+impl dyn CameraMatrix {
+    pub fn downcast_ref<T: CameraMatrix>(&self) -> Option<&T> { self.as_any().downcast_ref() }
+    pub fn downcast_mut<T: CameraMatrix>(&mut self) -> Option<&mut T> {
+        self.as_any_mut().downcast_mut()
+    }
+}
+
 pub struct CameraComponent {
     pub clear_color: Option<wgpu::Color>,
     pub matrix: Box<dyn CameraMatrix>,
     pub is_enabled: bool,
 }
 
-pub struct PerspectiveCameraMatrix(pub Perspective3<f32>);
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Serialize, Deserialize)]
+pub enum PerspectiveDepth {
+    /// nalgebra's projection between znear and zfar, see [`ClipDepth::NegativeOneToOne`]
+    Finite,
+    /// Reverse-Z projection whose far plane is at infinity, zfar is ignored, see
+    /// [`ClipDepth::OneToZero`]
+    ReversedInfinite,
+}
+
+pub struct PerspectiveCameraMatrix(pub Perspective3<f32>, pub PerspectiveDepth);
 impl PerspectiveCameraMatrix {
-    pub fn new() -> Self { Self(Perspective3::new(1., 60.0f32.to_radians(), 0.01, 200.)) }
+    pub fn new() -> Self {
+        Self(
+            Perspective3::new(1., 60.0f32.to_radians(), 0.01, 200.),
+            PerspectiveDepth::Finite,
+        )
+    }
+    /// Reverse-Z projection without far plane, to be used with
+    /// [`RendererConfig::reverse_z`](crate::renderer::RendererConfig::reverse_z)
+    pub fn reversed_infinite(aspect: f32, fovy: f32, znear: f32) -> Self {
+        // The far plane is unused but has to differ from the near one
+        Self(
+            Perspective3::new(aspect, fovy, znear, znear * 2.),
+            PerspectiveDepth::ReversedInfinite,
+        )
+    }
 }
 impl CameraMatrix for PerspectiveCameraMatrix {
-    fn get_projection_matrix(&self) -> Matrix4<f32> { self.0.to_homogeneous() }
+    fn clip_depth(&self) -> ClipDepth {
+        match self.1 {
+            PerspectiveDepth::Finite => ClipDepth::NegativeOneToOne,
+            PerspectiveDepth::ReversedInfinite => ClipDepth::OneToZero,
+        }
+    }
+    fn set_aspect(&mut self, aspect: f32) { self.0.set_aspect(aspect) }
+    fn get_projection_matrix(&self) -> Matrix4<f32> {
+        match self.1 {
+            PerspectiveDepth::Finite => self.0.to_homogeneous(),
+            PerspectiveDepth::ReversedInfinite => {
+                let focal = 1. / (self.0.fovy() / 2.).tan();
+                #[rustfmt::skip]
+                let projection = Matrix4::new(
+                    focal / self.0.aspect(), 0.,    0.,  0.,
+                    0.,                      focal, 0.,  0.,
+                    0.,                      0.,    0.,  self.0.znear(),
+                    0.,                      0.,    -1., 0.,
+                );
+                projection
+            }
+        }
+    }
+}
+
+pub struct OrthographicCameraMatrix(pub Orthographic3<f32>);
+impl OrthographicCameraMatrix {
+    /// View of `height` world units centered on the camera, its width follows the aspect ratio
+    pub fn from_size(height: f32, aspect: f32, znear: f32, zfar: f32) -> Self {
+        let (half_width, half_height) = (height * aspect / 2., height / 2.);
+        Self(Orthographic3::new(
+            -half_width,
+            half_width,
+            -half_height,
+            half_height,
+            znear,
+            zfar,
+        ))
+    }
+    pub fn from_bounds(
+        left: f32, right: f32, bottom: f32, top: f32, znear: f32, zfar: f32,
+    ) -> Self {
+        Self(Orthographic3::new(left, right, bottom, top, znear, zfar))
+    }
+}
+impl CameraMatrix for OrthographicCameraMatrix {
+    fn clip_depth(&self) -> ClipDepth { ClipDepth::ZeroToOne }
+    /// Changes the width of the view to match the aspect ratio, keeping its height and center
+    fn set_aspect(&mut self, aspect: f32) {
+        let center = (self.0.left() + self.0.right()) / 2.;
+        let half_width = (self.0.top() - self.0.bottom()) * aspect / 2.;
+        self.0
+            .set_left_and_right(center - half_width, center + half_width);
+    }
+
+    /// Unlike [`Orthographic3`] depth is mapped to wgpu's `[0, 1]` range
+    fn get_projection_matrix(&self) -> Matrix4<f32> {
+        ClipDepth::NegativeOneToOne.conversion_to(ClipDepth::ZeroToOne) * self.0.to_homogeneous()
+    }
+}
+
+/// Replaces the near plane of a projection by a plane given in view space, using Eric Lengyel's
+/// oblique frustum method
+///
+/// Points with `plane.xyz · p + plane.w >= 0` are kept, the camera has to be on the other side of
+/// the plane. The far plane is moved so that the frustum stays as tight as possible.
+pub fn oblique_projection(
+    projection: &Matrix4<f32>, clip_depth: ClipDepth, plane: &Vector4<f32>,
+) -> Matrix4<f32> {
+    let inverse = projection.try_inverse().unwrap();
+    // Corner of the frustum opposite to the plane, on the far plane
+    let clip_plane = inverse.transpose() * plane;
+    let corner = inverse
+        * Vector4::new(
+            clip_plane.x.signum(),
+            clip_plane.y.signum(),
+            clip_depth.far(),
+            1.,
+        );
+
+    let w_row = projection.row(3).transpose();
+    // Scales the plane so the corner stays on the far plane
+    let mut result = *projection;
+    match clip_depth {
+        // near: z + w >= 0, far: w - z >= 0
+        ClipDepth::NegativeOneToOne => {
+            let plane = plane * (2. * w_row.dot(&corner) / plane.dot(&corner));
+            result.set_row(2, &(plane - w_row).transpose());
+        }
+        // near: z >= 0, far: w - z >= 0
+        ClipDepth::ZeroToOne => {
+            let plane = plane * (w_row.dot(&corner) / plane.dot(&corner));
+            result.set_row(2, &plane.transpose());
+        }
+        // near: w - z >= 0, far: z >= 0
+        ClipDepth::OneToZero => {
+            let plane = plane * (w_row.dot(&corner) / plane.dot(&corner));
+            result.set_row(2, &(w_row - plane).transpose());
+        }
+    }
+    result
+}
+
+/// Camera whose near plane is replaced by a plane given in view space, for rendering through
+/// portals and mirrors, see [`oblique_projection`]
+///
+/// The plane depends on where the camera is, [`Self::set_world_clip_plane`] has to be called
+/// again when the camera or the plane moves.
+pub struct ObliqueCameraMatrix<M: CameraMatrix> {
+    pub inner: M,
+    pub clip_plane: Vector4<f32>,
+}
+impl<M: CameraMatrix> ObliqueCameraMatrix<M> {
+    /// Sets the clip plane from a world space plane, for the camera placed at `transform`
+    pub fn set_world_clip_plane(&mut self, transform: &TransformComponent, plane: &Vector4<f32>) {
+        // Planes are transformed by the inverse transpose of the point transformation
+        let view = self.inner.get_view_matrix(transform);
+        self.clip_plane = view.try_inverse().unwrap().transpose() * plane;
+    }
+}
+impl<M: CameraMatrix> CameraMatrix for ObliqueCameraMatrix<M> {
+    fn clip_depth(&self) -> ClipDepth { self.inner.clip_depth() }
+    fn set_aspect(&mut self, aspect: f32) { self.inner.set_aspect(aspect) }
+
+    fn get_view_matrix(&self, transform: &TransformComponent) -> Matrix4<f32> {
+        self.inner.get_view_matrix(transform)
+    }
+    fn get_projection_matrix(&self) -> Matrix4<f32> {
+        oblique_projection(
+            &self.inner.get_projection_matrix(),
+            self.clip_depth(),
+            &self.clip_plane,
+        )
+    }
 }
 
 pub struct PerspectiveCameraSystem(pub PerspectiveCameraMatrix);
+
+#[cfg(test)]
+mod tests {
+    use approx::assert_relative_eq;
+    use nalgebra::{Point3, Vector3};
+
+    use super::*;
+
+    #[test]
+    fn downcast_matrix() {
+        let mut matrix: Box<dyn CameraMatrix> = Box::new(PerspectiveCameraMatrix::new());
+        assert!(matrix.downcast_ref::<OrthographicCameraMatrix>().is_none());
+        matrix
+            .downcast_mut::<PerspectiveCameraMatrix>()
+            .unwrap()
+            .0
+            .set_fovy(1.);
+        assert_relative_eq!(
+            matrix
+                .downcast_ref::<PerspectiveCameraMatrix>()
+                .unwrap()
+                .0
+                .fovy(),
+            1.
+        );
+    }
+
+    fn assert_oblique_projection(matrix: &dyn CameraMatrix) {
+        let projection = matrix.get_projection_matrix();
+        let clip_depth = matrix.clip_depth();
+        let near_depth = clip_depth.near();
+        // Tilted plane 5 units in front of the camera, facing away from it
+        let normal = Vector3::new(0.3, -0.2, -1.).normalize();
+        let on_plane = Point3::new(0., 0., -5.);
+        let plane = normal.push(-normal.dot(&on_plane.coords));
+        let oblique = oblique_projection(&projection, matrix.clip_depth(), &plane);
+
+        let tangent = normal.cross(&Vector3::y()).normalize();
+        for point in &[on_plane, on_plane + tangent, on_plane - tangent * 0.5] {
+            // Points of the plane are on the new near plane
+            assert_relative_eq!(oblique.transform_point(point).z, near_depth, epsilon = 1e-4);
+            // X and Y are unchanged
+            assert_relative_eq!(
+                oblique.transform_point(point).xy(),
+                projection.transform_point(point).xy(),
+                epsilon = 1e-4
+            );
+        }
+
+        let behind = on_plane - normal;
+        assert!(clip_depth.is_closer(oblique.transform_point(&behind).z, near_depth));
+        let in_front = on_plane + normal;
+        let depth = oblique.transform_point(&in_front).z;
+        assert!(
+            clip_depth.is_closer(near_depth, depth)
+                && clip_depth.is_closer(depth, clip_depth.far())
+        );
+    }
+
+    #[test]
+    fn oblique_projections() {
+        assert_oblique_projection(&PerspectiveCameraMatrix::new());
+        assert_oblique_projection(&OrthographicCameraMatrix::from_size(10., 1., 0.1, 100.));
+        assert_oblique_projection(&PerspectiveCameraMatrix::reversed_infinite(1.5, 1., 0.1));
+
+        // World space plane z = 5 seen from a camera at z = 10
+        let mut matrix = ObliqueCameraMatrix {
+            inner: PerspectiveCameraMatrix::new(),
+            clip_plane: Vector4::zeros(),
+        };
+        let mut transform = TransformComponent::default();
+        transform.position.z = 10.;
+        matrix.set_world_clip_plane(&transform, &Vector4::new(0., 0., -1., 5.));
+        let vp = matrix.get_vp_matrix(&transform);
+        for point in &[Point3::new(0., 0., 5.), Point3::new(1., -2., 5.)] {
+            assert_relative_eq!(vp.transform_point(point).z, -1., epsilon = 1e-4);
+        }
+        assert!(vp.transform_point(&Point3::new(0., 0., 6.)).z < -1.);
+        // Depths and rays start on the clip plane
+        assert_relative_eq!(
+            matrix.linear_depth(-1., ClipDepth::NegativeOneToOne),
+            5.,
+            epsilon = 1e-3
+        );
+        let (origin, direction) = matrix.screen_ray(&transform, &Point2::origin());
+        assert_relative_eq!(origin, Point3::new(0., 0., 5.), epsilon = 1e-3);
+        assert_relative_eq!(*direction, -Vector3::z(), epsilon = 1e-4);
+    }
+
+    #[test]
+    fn orthographic_projection() {
+        let mut camera = OrthographicCameraMatrix::from_bounds(-2., 2., -1., 1., 1., 11.);
+        let project = |camera: &OrthographicCameraMatrix, p| {
+            camera.get_projection_matrix().transform_point(&p)
+        };
+        // The camera looks towards -Z
+        assert_relative_eq!(
+            project(&camera, Point3::new(-2., -1., -1.)),
+            Point3::new(-1., -1., 0.)
+        );
+        assert_relative_eq!(
+            project(&camera, Point3::new(2., 1., -11.)),
+            Point3::new(1., 1., 1.)
+        );
+
+        camera.set_aspect(1.);
+        assert_relative_eq!(camera.0.left(), -1.);
+        assert_relative_eq!(camera.0.right(), 1.);
+        assert_relative_eq!(
+            OrthographicCameraMatrix::from_size(2., 1., 1., 11.).get_projection_matrix(),
+            camera.get_projection_matrix()
+        );
+    }
+
+    #[test]
+    fn reversed_infinite_projection() {
+        let camera = PerspectiveCameraMatrix::reversed_infinite(2., 1., 0.5);
+        let projection = camera.get_projection_matrix();
+        assert_relative_eq!(projection.transform_point(&Point3::new(0., 0., -0.5)).z, 1.);
+        assert_relative_eq!(projection.transform_point(&Point3::new(0., 0., -5.)).z, 0.1);
+        assert!(projection.transform_point(&Point3::new(0., 0., -1e7)).z < 1e-6);
+        // X and Y match the finite projection
+        let finite = Perspective3::new(2., 1., 0.5, 100.);
+        let point = Point3::new(1., -2., -3.);
+        assert_relative_eq!(
+            projection.transform_point(&point).xy(),
+            finite.project_point(&point).xy(),
+            epsilon = 1e-6
+        );
+
+        assert_relative_eq!(
+            camera.linear_depth(0.1, ClipDepth::OneToZero),
+            5.,
+            epsilon = 1e-4
+        );
+        assert_relative_eq!(
+            camera.linear_depth(0.9, ClipDepth::ZeroToOne),
+            5.,
+            epsilon = 1e-4
+        );
+        assert_eq!(camera.linear_depth(0., ClipDepth::OneToZero), f32::INFINITY);
+    }
+
+    #[test]
+    fn depth_conversions() {
+        let conventions = [
+            ClipDepth::NegativeOneToOne,
+            ClipDepth::ZeroToOne,
+            ClipDepth::OneToZero,
+        ];
+        for &from in &conventions {
+            for &to in &conventions {
+                let conversion = from.conversion_to(to);
+                for &(depth, target) in &[(from.near(), to.near()), (from.far(), to.far())] {
+                    let point = conversion.transform_point(&Point3::new(0.5, -1., depth));
+                    assert_relative_eq!(point, Point3::new(0.5, -1., target));
+                }
+            }
+        }
+        let perspective = PerspectiveCameraMatrix::new();
+        assert_relative_eq!(
+            perspective.linear_depth(-1., ClipDepth::NegativeOneToOne),
+            perspective.0.znear(),
+            epsilon = 1e-4
+        );
+        assert_relative_eq!(
+            perspective.linear_depth(1., ClipDepth::ZeroToOne),
+            perspective.0.zfar(),
+            max_relative = 1e-2
+        );
+    }
+
+    #[test]
+    fn screen_rays() {
+        let transform = TransformComponent {
+            position: Vector3::new(1., 2., 3.),
+            ..Default::default()
+        };
+        for camera in &[
+            PerspectiveCameraMatrix::new(),
+            PerspectiveCameraMatrix::reversed_infinite(1., 1., 0.1),
+        ] {
+            let (origin, direction) = camera.screen_ray(&transform, &Point2::origin());
+            assert_relative_eq!(
+                origin,
+                Point3::new(1., 2., 3. - camera.0.znear()),
+                epsilon = 1e-4
+            );
+            assert_relative_eq!(*direction, -Vector3::z(), epsilon = 1e-4);
+        }
+    }
+}
@@ -1,68 +1,614 @@
+use std::{
+    collections::{HashMap, HashSet, VecDeque},
+    fmt,
+};
+
 use smallvec::SmallVec;
 
 pub struct ParentComponent(pub Option<hecs::Entity>);
 pub struct ChildrenComponent(pub SmallVec<[hecs::Entity; 8]>);
 
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum HierarchyError {
+    NoSuchEntity(hecs::Entity),
+    /// The child is the parent itself or one of its ancestors
+    Cycle {
+        parent: hecs::Entity,
+        child: hecs::Entity,
+    },
+}
+impl fmt::Display for HierarchyError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            HierarchyError::NoSuchEntity(entity) => write!(f, "No such entity {:?}", entity),
+            HierarchyError::Cycle { parent, child } => write!(
+                f,
+                "Making {:?} a child of {:?} would create a cycle",
+                child, parent
+            ),
+        }
+    }
+}
+impl std::error::Error for HierarchyError {}
+
 pub trait WorldExt {
+    /// Makes the entity a child of the parent, removing it from its previous parent and adding
+    /// the hierarchy components that are missing
     fn add_child(
-        &self, parent_entity: hecs::Entity, child_entity: hecs::Entity,
-    ) -> Result<(), hecs::ComponentError>;
-    fn remove_parent(&self, child_entity: hecs::Entity) -> Result<(), hecs::ComponentError>;
+        &mut self, parent_entity: hecs::Entity, child_entity: hecs::Entity,
+    ) -> Result<(), HierarchyError>;
+    fn remove_parent(&mut self, child_entity: hecs::Entity) -> Result<(), HierarchyError>;
+
+    /// Despawns the entity and all its descendants, removing it from its parent's children
+    fn despawn_recursive(&mut self, entity: hecs::Entity) -> Result<(), hecs::NoSuchEntity>;
+    /// Descendants of the entity in depth-first order, children before their siblings' children
+    fn descendants(&self, entity: hecs::Entity) -> Descendants<'_>;
+    /// Descendants of the entity in breadth-first order, by increasing depth
+    fn descendants_breadth_first(&self, entity: hecs::Entity) -> BreadthFirstDescendants<'_>;
+    /// Parent of the entity, then its grandparent, up to the root
+    fn ancestors(&self, entity: hecs::Entity) -> Ancestors<'_>;
+    /// Highest ancestor of the entity, the entity itself if it has no parent
+    fn root_of(&self, entity: hecs::Entity) -> hecs::Entity;
+    fn is_ancestor_of(&self, ancestor: hecs::Entity, entity: hecs::Entity) -> bool;
+}
+
+fn children_of(world: &hecs::World, entity: hecs::Entity) -> SmallVec<[hecs::Entity; 8]> {
+    world
+        .get::<ChildrenComponent>(entity)
+        .map(|children| children.0.clone())
+        .unwrap_or_default()
+}
+
+/// Children of the entity whose [`ParentComponent`] points back to it, the others are skipped so
+/// walks agree with [`update_global_transforms`](crate::transform::update_global_transforms)
+fn linked_children(
+    world: &hecs::World, entity: hecs::Entity,
+) -> impl DoubleEndedIterator<Item = hecs::Entity> + '_ {
+    children_of(world, entity)
+        .into_iter()
+        .filter(move |&child| live_parent(world, child) == Some(entity))
+}
+
+pub struct Descendants<'w> {
+    world: &'w hecs::World,
+    stack: Vec<hecs::Entity>,
+    /// Stops on corrupted hierarchies with cycles or entities listed twice
+    visited: HashSet<hecs::Entity>,
+}
+impl Iterator for Descendants<'_> {
+    type Item = hecs::Entity;
+
+    fn next(&mut self) -> Option<hecs::Entity> {
+        let entity = loop {
+            let entity = self.stack.pop()?;
+            if self.visited.insert(entity) {
+                break entity;
+            }
+        };
+        self.stack.extend(linked_children(self.world, entity).rev());
+        Some(entity)
+    }
+}
+
+pub struct BreadthFirstDescendants<'w> {
+    world: &'w hecs::World,
+    queue: VecDeque<hecs::Entity>,
+    /// Stops on corrupted hierarchies with cycles or entities listed twice
+    visited: HashSet<hecs::Entity>,
+}
+impl Iterator for BreadthFirstDescendants<'_> {
+    type Item = hecs::Entity;
+
+    fn next(&mut self) -> Option<hecs::Entity> {
+        let entity = loop {
+            let entity = self.queue.pop_front()?;
+            if self.visited.insert(entity) {
+                break entity;
+            }
+        };
+        self.queue.extend(linked_children(self.world, entity));
+        Some(entity)
+    }
+}
+
+pub struct Ancestors<'w> {
+    world: &'w hecs::World,
+    current: hecs::Entity,
+    /// Stops on corrupted hierarchies with cycles
+    remaining: u32,
+}
+impl Iterator for Ancestors<'_> {
+    type Item = hecs::Entity;
+
+    fn next(&mut self) -> Option<hecs::Entity> {
+        self.remaining = self.remaining.checked_sub(1)?;
+        let parent = self
+            .world
+            .get::<ParentComponent>(self.current)
+            .ok()?
+            .0
+            .filter(|&parent| self.world.contains(parent))?;
+        self.current = parent;
+        Some(parent)
+    }
 }
 impl WorldExt for hecs::World {
     fn add_child(
-        &self, parent_entity: hecs::Entity, child_entity: hecs::Entity,
-    ) -> Result<(), hecs::ComponentError> {
-        let mut children = self.get_mut::<ChildrenComponent>(parent_entity)?;
-        let mut parent = self.get_mut::<ParentComponent>(child_entity)?;
-        if let Some(old_parent_entity) = parent.0 {
-            let mut old_children = self.get_mut::<ChildrenComponent>(old_parent_entity)?;
-            let child_index = old_children
-                .0
-                .iter()
-                .copied()
-                .enumerate()
-                .find_map(|(a, b)| {
-                    if b == child_entity {
-                        Some(a)
-                    }
-                    else {
-                        None
-                    }
-                })
-                .unwrap();
-            old_children.0.swap_remove(child_index);
-        }
-        if children.0.contains(&child_entity) {
-            return Ok(());
-        }
-        children.0.push(child_entity);
-        parent.0 = Some(parent_entity);
+        &mut self, parent_entity: hecs::Entity, child_entity: hecs::Entity,
+    ) -> Result<(), HierarchyError> {
+        for &entity in &[parent_entity, child_entity] {
+            if !self.contains(entity) {
+                return Err(HierarchyError::NoSuchEntity(entity));
+            }
+        }
+        if parent_entity == child_entity || self.is_ancestor_of(child_entity, parent_entity) {
+            return Err(HierarchyError::Cycle {
+                parent: parent_entity,
+                child: child_entity,
+            });
+        }
+        self.remove_parent(child_entity)?;
+
+        if self.get::<ChildrenComponent>(parent_entity).is_err() {
+            self.insert_one(parent_entity, ChildrenComponent(SmallVec::new()))
+                .map_err(|_| HierarchyError::NoSuchEntity(parent_entity))?;
+        }
+        let mut children = self.get_mut::<ChildrenComponent>(parent_entity).unwrap();
+        if !children.0.contains(&child_entity) {
+            children.0.push(child_entity);
+        }
+        self.get_mut::<ParentComponent>(child_entity).unwrap().0 = Some(parent_entity);
         Ok(())
     }
 
     /// Alias: Orphaner
-    fn remove_parent(&self, child_entity: hecs::Entity) -> Result<(), hecs::ComponentError> {
-        let mut parent = self.get_mut::<ParentComponent>(child_entity)?;
-        if let Some(old_parent_entity) = parent.0 {
-            let mut old_children = self.get_mut::<ChildrenComponent>(old_parent_entity)?;
-            let child_index = old_children
-                .0
-                .iter()
-                .copied()
-                .enumerate()
-                .find_map(|(a, b)| {
-                    if b == child_entity {
-                        Some(a)
-                    }
-                    else {
-                        None
-                    }
-                })
-                .unwrap();
-            old_children.0.swap_remove(child_index);
-        }
-        parent.0 = None;
+    fn remove_parent(&mut self, child_entity: hecs::Entity) -> Result<(), HierarchyError> {
+        let old_parent = match self.get_mut::<ParentComponent>(child_entity) {
+            Ok(mut parent) => parent.0.take(),
+            Err(hecs::ComponentError::MissingComponent(_)) => None,
+            Err(hecs::ComponentError::NoSuchEntity) => {
+                return Err(HierarchyError::NoSuchEntity(child_entity))
+            }
+        };
+        if old_parent.is_none() && self.get::<ParentComponent>(child_entity).is_err() {
+            self.insert_one(child_entity, ParentComponent(None))
+                .map_err(|_| HierarchyError::NoSuchEntity(child_entity))?;
+        }
+        // The old parent may be despawned or not list the child
+        if let Some(mut old_children) =
+            old_parent.and_then(|parent| self.get_mut::<ChildrenComponent>(parent).ok())
+        {
+            old_children.0.retain(|&mut child| child != child_entity);
+        }
         Ok(())
     }
+
+    fn despawn_recursive(&mut self, entity: hecs::Entity) -> Result<(), hecs::NoSuchEntity> {
+        if !self.contains(entity) {
+            return Err(hecs::NoSuchEntity);
+        }
+        let parent = self.get::<ParentComponent>(entity).ok().and_then(|p| p.0);
+        if let Some(mut siblings) = parent.and_then(|p| self.get_mut::<ChildrenComponent>(p).ok()) {
+            siblings.0.retain(|&mut sibling| sibling != entity);
+        }
+        let descendants = self.descendants(entity).collect::<Vec<_>>();
+        self.despawn(entity)?;
+        // Children lists can contain despawned or duplicated entities
+        for descendant in descendants {
+            if self.contains(descendant) {
+                self.despawn(descendant)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn descendants(&self, entity: hecs::Entity) -> Descendants<'_> {
+        Descendants {
+            world: self,
+            stack: linked_children(self, entity).rev().collect(),
+            visited: std::iter::once(entity).collect(),
+        }
+    }
+
+    fn descendants_breadth_first(&self, entity: hecs::Entity) -> BreadthFirstDescendants<'_> {
+        BreadthFirstDescendants {
+            world: self,
+            queue: linked_children(self, entity).collect(),
+            visited: std::iter::once(entity).collect(),
+        }
+    }
+
+    fn ancestors(&self, entity: hecs::Entity) -> Ancestors<'_> {
+        Ancestors {
+            world: self,
+            current: entity,
+            remaining: self.len(),
+        }
+    }
+
+    fn root_of(&self, entity: hecs::Entity) -> hecs::Entity {
+        self.ancestors(entity).last().unwrap_or(entity)
+    }
+
+    fn is_ancestor_of(&self, ancestor: hecs::Entity, entity: hecs::Entity) -> bool {
+        self.ancestors(entity).any(|e| e == ancestor)
+    }
+}
+
+/// Inconsistency found by [`validate_hierarchy`]
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum HierarchyIssue {
+    /// The child's [`ParentComponent`] points to a despawned entity
+    DeadParent {
+        child: hecs::Entity,
+        parent: hecs::Entity,
+    },
+    /// The parent's [`ChildrenComponent`] lists a despawned entity
+    DeadChild {
+        parent: hecs::Entity,
+        child: hecs::Entity,
+    },
+    DuplicateChild {
+        parent: hecs::Entity,
+        child: hecs::Entity,
+    },
+    /// The parent lists a child whose [`ParentComponent`] doesn't point back to it
+    MissingParentLink {
+        parent: hecs::Entity,
+        child: hecs::Entity,
+    },
+    /// The child's [`ParentComponent`] points to a parent that doesn't list it
+    MissingChildLink {
+        parent: hecs::Entity,
+        child: hecs::Entity,
+    },
+    /// The entity is its own ancestor, reported once per cycle for its smallest entity
+    Cycle { entity: hecs::Entity },
+}
+impl fmt::Display for HierarchyIssue {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            HierarchyIssue::DeadParent { child, parent } => {
+                write!(f, "{:?} has the despawned parent {:?}", child, parent)
+            }
+            HierarchyIssue::DeadChild { parent, child } => {
+                write!(f, "{:?} has the despawned child {:?}", parent, child)
+            }
+            HierarchyIssue::DuplicateChild { parent, child } => {
+                write!(f, "{:?} lists its child {:?} several times", parent, child)
+            }
+            HierarchyIssue::MissingParentLink { parent, child } => write!(
+                f,
+                "{:?} lists {:?} as a child but isn't its parent",
+                parent, child
+            ),
+            HierarchyIssue::MissingChildLink { parent, child } => write!(
+                f,
+                "{:?} is the parent of {:?} but doesn't list it",
+                parent, child
+            ),
+            HierarchyIssue::Cycle { entity } => write!(f, "{:?} is its own ancestor", entity),
+        }
+    }
+}
+
+fn live_parent(world: &hecs::World, entity: hecs::Entity) -> Option<hecs::Entity> {
+    world
+        .get::<ParentComponent>(entity)
+        .ok()?
+        .0
+        .filter(|&parent| world.contains(parent))
+}
+
+/// Checks that [`ParentComponent`]s and [`ChildrenComponent`]s agree with each other, issues are
+/// grouped by check and sorted by entity so repairs are deterministic
+pub fn validate_hierarchy(world: &hecs::World) -> Vec<HierarchyIssue> {
+    let mut issues = Vec::new();
+
+    let mut parents = world
+        .query::<&ParentComponent>()
+        .iter()
+        .filter_map(|(child, parent)| parent.0.map(|parent| (child, parent)))
+        .collect::<Vec<_>>();
+    parents.sort();
+    for &(child, parent) in &parents {
+        if !world.contains(parent) {
+            issues.push(HierarchyIssue::DeadParent { child, parent });
+        }
+        else if !children_of(world, parent).contains(&child) {
+            issues.push(HierarchyIssue::MissingChildLink { parent, child });
+        }
+    }
+
+    let mut children_lists = world
+        .query::<&ChildrenComponent>()
+        .iter()
+        .map(|(parent, children)| (parent, children.0.clone()))
+        .collect::<Vec<_>>();
+    children_lists.sort_by_key(|(parent, _)| *parent);
+    for (parent, children) in children_lists {
+        for (i, &child) in children.iter().enumerate() {
+            if !world.contains(child) {
+                issues.push(HierarchyIssue::DeadChild { parent, child });
+            }
+            else if children[..i].contains(&child) {
+                issues.push(HierarchyIssue::DuplicateChild { parent, child });
+            }
+            else if live_parent(world, child) != Some(parent) {
+                issues.push(HierarchyIssue::MissingParentLink { parent, child });
+            }
+        }
+    }
+
+    for &(entity, _) in &parents {
+        let mut cycle = vec![entity];
+        let mut current = entity;
+        while let Some(parent) = live_parent(world, current) {
+            if parent == entity {
+                if cycle.iter().min() == Some(&entity) {
+                    issues.push(HierarchyIssue::Cycle { entity });
+                }
+                break;
+            }
+            // Leads to a cycle that doesn't contain the entity
+            if cycle.contains(&parent) {
+                break;
+            }
+            cycle.push(parent);
+            current = parent;
+        }
+    }
+    issues
+}
+
+/// Fixes the issues reported by [`validate_hierarchy`] and returns them
+///
+/// [`ParentComponent`]s are trusted: children lists are rebuilt to match them, dead parents are
+/// removed and cycles are broken by detaching their smallest entity from its parent.
+pub fn repair_hierarchy(world: &mut hecs::World) -> Vec<HierarchyIssue> {
+    let issues = validate_hierarchy(world);
+    if issues.is_empty() {
+        return issues;
+    }
+
+    for issue in &issues {
+        match *issue {
+            HierarchyIssue::DeadParent { child: entity, .. } | HierarchyIssue::Cycle { entity } => {
+                world.get_mut::<ParentComponent>(entity).unwrap().0 = None;
+            }
+            _ => {}
+        }
+    }
+
+    let parents = world
+        .query::<&ParentComponent>()
+        .iter()
+        .map(|(child, parent)| (child, parent.0))
+        .collect::<HashMap<_, _>>();
+    for (parent, children) in world.query_mut::<&mut ChildrenComponent>() {
+        let mut kept = SmallVec::<[hecs::Entity; 8]>::new();
+        for &child in &children.0 {
+            if parents.get(&child) == Some(&Some(parent)) && !kept.contains(&child) {
+                kept.push(child);
+            }
+        }
+        children.0 = kept;
+    }
+
+    for issue in &issues {
+        if let HierarchyIssue::MissingChildLink { parent, child } = *issue {
+            // The link may have been removed to break a cycle
+            if parents[&child] != Some(parent) {
+                continue;
+            }
+            if world.get::<ChildrenComponent>(parent).is_err() {
+                world
+                    .insert_one(parent, ChildrenComponent(SmallVec::new()))
+                    .unwrap();
+            }
+            world
+                .get_mut::<ChildrenComponent>(parent)
+                .unwrap()
+                .0
+                .push(child);
+        }
+    }
+    issues
+}
+
+/// Repairs the hierarchy and prints what was wrong in debug builds, meant to be called every
+/// frame, does nothing in release builds
+pub fn debug_repair_hierarchy(world: &mut hecs::World) {
+    #[cfg(debug_assertions)]
+    for issue in repair_hierarchy(world) {
+        eprintln!("Repaired hierarchy: {}", issue);
+    }
+    #[cfg(not(debug_assertions))]
+    let _ = world;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn spawn_node(world: &mut hecs::World) -> hecs::Entity {
+        world.spawn((ParentComponent(None), ChildrenComponent(SmallVec::new())))
+    }
+
+    #[test]
+    fn traversal() {
+        let mut world = hecs::World::new();
+        // root -> (a -> (c, d), b -> e)
+        let nodes = (0..6).map(|_| spawn_node(&mut world)).collect::<Vec<_>>();
+        let (root, a, b, c, d, e) = (nodes[0], nodes[1], nodes[2], nodes[3], nodes[4], nodes[5]);
+        for &(parent, child) in &[(root, a), (root, b), (a, c), (a, d), (b, e)] {
+            world.add_child(parent, child).unwrap();
+        }
+
+        assert_eq!(world.descendants(root).collect::<Vec<_>>(), vec![
+            a, c, d, b, e
+        ]);
+        assert_eq!(
+            world.descendants_breadth_first(root).collect::<Vec<_>>(),
+            vec![a, b, c, d, e]
+        );
+        assert_eq!(world.ancestors(d).collect::<Vec<_>>(), vec![a, root]);
+        assert_eq!(world.root_of(e), root);
+        assert_eq!(world.root_of(root), root);
+        assert!(world.is_ancestor_of(root, c));
+        assert!(!world.is_ancestor_of(b, c));
+        assert!(!world.is_ancestor_of(c, c));
+
+        world.despawn_recursive(a).unwrap();
+        for entity in &[a, c, d] {
+            assert!(!world.contains(*entity));
+        }
+        assert_eq!(
+            world.get::<ChildrenComponent>(root).unwrap().0.as_slice(),
+            &[b]
+        );
+        assert_eq!(world.descendants(root).collect::<Vec<_>>(), vec![b, e]);
+        assert!(world.despawn_recursive(a).is_err());
+
+        // Dangling children are skipped
+        let f = spawn_node(&mut world);
+        world.add_child(b, f).unwrap();
+        world.despawn(e).unwrap();
+        world.despawn_recursive(root).unwrap();
+        for entity in &[root, b, f] {
+            assert!(!world.contains(*entity));
+        }
+    }
+
+    #[test]
+    fn hierarchy_errors() {
+        let mut world = hecs::World::new();
+        let root = spawn_node(&mut world);
+        // Components are added when missing
+        let child = world.spawn(());
+        let grandchild = world.spawn(());
+        world.add_child(root, child).unwrap();
+        world.add_child(child, grandchild).unwrap();
+        assert_eq!(world.root_of(grandchild), root);
+
+        assert_eq!(
+            world.add_child(grandchild, root),
+            Err(HierarchyError::Cycle {
+                parent: grandchild,
+                child: root,
+            })
+        );
+        assert!(world.add_child(child, child).is_err());
+        assert_eq!(world.ancestors(grandchild).collect::<Vec<_>>(), vec![
+            child, root
+        ]);
+
+        // Inconsistent back-links don't panic
+        world.get_mut::<ChildrenComponent>(root).unwrap().0.clear();
+        let other = world.spawn(());
+        world.add_child(other, child).unwrap();
+        assert_eq!(world.root_of(grandchild), other);
+        world.despawn(other).unwrap();
+        world.remove_parent(child).unwrap();
+        assert_eq!(world.get::<ParentComponent>(child).unwrap().0, None);
+
+        let dead = world.spawn(());
+        world.despawn(dead).unwrap();
+        assert_eq!(
+            world.add_child(child, dead),
+            Err(HierarchyError::NoSuchEntity(dead))
+        );
+        assert!(world.remove_parent(dead).is_err());
+
+        // Duplicates and children that don't link back are skipped
+        world.get_mut::<ChildrenComponent>(child).unwrap().0 =
+            [grandchild, grandchild, child].iter().copied().collect();
+        world
+            .insert_one(
+                grandchild,
+                ChildrenComponent([child, root].iter().copied().collect()),
+            )
+            .unwrap();
+        assert_eq!(world.descendants(child).collect::<Vec<_>>(), vec![
+            grandchild
+        ]);
+        assert_eq!(
+            world.descendants_breadth_first(child).collect::<Vec<_>>(),
+            vec![grandchild]
+        );
+        world.despawn_recursive(child).unwrap();
+        assert!(!world.contains(child));
+        assert!(!world.contains(grandchild));
+        assert!(world.contains(root));
+    }
+
+    #[test]
+    fn validate_and_repair() {
+        let mut world = hecs::World::new();
+        let nodes = (0..6).map(|_| spawn_node(&mut world)).collect::<Vec<_>>();
+        let (root, a, b, c, d, dead) = (nodes[0], nodes[1], nodes[2], nodes[3], nodes[4], nodes[5]);
+        world.add_child(root, a).unwrap();
+        world.add_child(root, b).unwrap();
+        assert_eq!(validate_hierarchy(&world), vec![]);
+
+        world.despawn(dead).unwrap();
+        world.get_mut::<ChildrenComponent>(root).unwrap().0 =
+            [a, a, dead, c].iter().copied().collect();
+        // b isn't listed anymore, and c and d form a cycle
+        world.get_mut::<ParentComponent>(c).unwrap().0 = Some(d);
+        world.get_mut::<ParentComponent>(d).unwrap().0 = Some(c);
+        world.get_mut::<ParentComponent>(a).unwrap().0 = Some(root);
+        let orphan = world.spawn((ParentComponent(Some(dead)),));
+
+        let mut expected = vec![
+            HierarchyIssue::MissingChildLink {
+                parent: root,
+                child: b,
+            },
+            HierarchyIssue::MissingChildLink {
+                parent: d,
+                child: c,
+            },
+            HierarchyIssue::MissingChildLink {
+                parent: c,
+                child: d,
+            },
+            HierarchyIssue::DeadParent {
+                child: orphan,
+                parent: dead,
+            },
+            HierarchyIssue::DuplicateChild {
+                parent: root,
+                child: a,
+            },
+            HierarchyIssue::DeadChild {
+                parent: root,
+                child: dead,
+            },
+            HierarchyIssue::MissingParentLink {
+                parent: root,
+                child: c,
+            },
+            HierarchyIssue::Cycle { entity: c },
+        ];
+        let mut issues = validate_hierarchy(&world);
+        expected.sort_by_key(|issue| format!("{:?}", issue));
+        issues.sort_by_key(|issue| format!("{:?}", issue));
+        assert_eq!(issues, expected);
+
+        assert_eq!(repair_hierarchy(&mut world).len(), expected.len());
+        assert_eq!(validate_hierarchy(&world), vec![]);
+        assert_eq!(
+            world.get::<ChildrenComponent>(root).unwrap().0.as_slice(),
+            &[a, b]
+        );
+        assert_eq!(world.get::<ParentComponent>(orphan).unwrap().0, None);
+        // The cycle is broken at its smallest entity
+        assert_eq!(world.get::<ParentComponent>(c).unwrap().0, None);
+        assert_eq!(world.ancestors(d).collect::<Vec<_>>(), vec![c]);
+        assert_eq!(world.get::<ChildrenComponent>(c).unwrap().0.as_slice(), &[
+            d
+        ]);
+        assert!(repair_hierarchy(&mut world).is_empty());
+    }
 }
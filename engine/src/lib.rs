@@ -2,4 +2,11 @@ pub mod camera;
 pub mod hecs_extension;
 pub mod renderer;
 pub mod resource_manager;
+pub mod scene;
+pub mod time;
 pub mod transform;
+
+// Lets the derive macros refer to the engine as `::portal_engine` from inside the crate too
+extern crate self as portal_engine;
+#[doc(hidden)]
+pub use memoffset;
@@ -1,15 +1,167 @@
-use std::marker::PhantomData;
+use std::{collections::HashMap, marker::PhantomData};
 
+use anyhow::{anyhow, bail};
+use nalgebra::Matrix4;
 use smallvec::SmallVec;
 
 use super::*;
 
 pub struct Material {
     pub shader: ShaderRef,
-    pub render_pipeline: wgpu::RenderPipeline,
+    /// Pipelines for each primitive state used by the meshes of the material
+    pub render_pipelines: HashMap<PipelineKey, wgpu::RenderPipeline>,
     pub bind_groups: SmallVec<[wgpu::BindGroup; 2]>,
+    pub cull_mode: Option<wgpu::Face>,
+    pub(crate) stencil: wgpu::StencilState,
+    pub(crate) stencil_reference: u32,
+    /// Set when the stencil state changed and the pipelines have to be recreated
+    pub(crate) pipelines_dirty: bool,
+
+    /// Parameters of each of the shader's bind groups after the render uniforms
+    pub(crate) parameters: SmallVec<[SmallVec<[MaterialParameter; 4]>; 2]>,
+    /// Set when a texture or sampler changed and the bind groups have to be recreated
+    pub(crate) bind_groups_dirty: bool,
 
     pub(crate) marker: PhantomData<()>,
 }
-#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Hash)]
 pub struct MaterialRef(pub(crate) usize);
+
+pub(crate) struct MaterialParameter {
+    pub(crate) reflection: ReflectedBinding,
+    pub(crate) value: MaterialParameterValue,
+}
+pub(crate) enum MaterialParameterValue {
+    Uniform {
+        data: Vec<u8>,
+        buffer: wgpu::Buffer,
+        dirty: bool,
+    },
+    Texture(TextureRef),
+    Sampler(TextureRef),
+}
+
+impl Material {
+    fn find_uniform_member(
+        &mut self, name: &str,
+    ) -> anyhow::Result<(&ReflectedMember, &mut Vec<u8>, &mut bool)> {
+        self.parameters
+            .iter_mut()
+            .flatten()
+            .find_map(|parameter| match &mut parameter.value {
+                MaterialParameterValue::Uniform { data, dirty, .. } => parameter
+                    .reflection
+                    .members
+                    .iter()
+                    .find(|member| member.name.as_deref() == Some(name))
+                    .map(|member| (member, data, dirty)),
+                _ => None,
+            })
+            .ok_or_else(|| anyhow!("No uniform named '{}' in the material", name))
+    }
+    fn set_uniform_typed(
+        &mut self, name: &str, ty: Option<UniformType>, bytes: &[u8],
+    ) -> anyhow::Result<()> {
+        let (member, data, dirty) = self.find_uniform_member(name)?;
+        if let Some(ty) = ty {
+            if member.ty != ty {
+                bail!("Uniform '{}' is a {:?}, not a {:?}", name, member.ty, ty);
+            }
+        }
+        if member.size as usize != bytes.len() {
+            bail!(
+                "Uniform '{}' is {} bytes long, got {} bytes",
+                name,
+                member.size,
+                bytes.len()
+            );
+        }
+        let offset = member.offset as usize;
+        data[offset..offset + bytes.len()].copy_from_slice(bytes);
+        *dirty = true;
+        Ok(())
+    }
+
+    /// Sets a member of one of the material's uniform structs from raw data of the same size
+    pub fn set_uniform<T: Pod>(&mut self, name: &str, value: &T) -> anyhow::Result<()> {
+        self.set_uniform_typed(name, None, bytemuck::bytes_of(value))
+    }
+    pub fn set_float(&mut self, name: &str, value: f32) -> anyhow::Result<()> {
+        self.set_uniform_typed(name, Some(UniformType::Float), bytemuck::bytes_of(&value))
+    }
+    pub fn set_vec2(&mut self, name: &str, value: [f32; 2]) -> anyhow::Result<()> {
+        self.set_uniform_typed(name, Some(UniformType::Vec2), bytemuck::bytes_of(&value))
+    }
+    pub fn set_vec3(&mut self, name: &str, value: [f32; 3]) -> anyhow::Result<()> {
+        self.set_uniform_typed(name, Some(UniformType::Vec3), bytemuck::bytes_of(&value))
+    }
+    pub fn set_vec4(&mut self, name: &str, value: [f32; 4]) -> anyhow::Result<()> {
+        self.set_uniform_typed(name, Some(UniformType::Vec4), bytemuck::bytes_of(&value))
+    }
+    pub fn set_mat4(&mut self, name: &str, value: &Matrix4<f32>) -> anyhow::Result<()> {
+        self.set_uniform_typed(
+            name,
+            Some(UniformType::Mat4),
+            bytemuck::cast_slice(value.as_slice()),
+        )
+    }
+
+    fn find_binding(&mut self, name: &str) -> anyhow::Result<&mut MaterialParameterValue> {
+        self.parameters
+            .iter_mut()
+            .flatten()
+            .find(|parameter| parameter.reflection.name.as_deref() == Some(name))
+            .map(|parameter| &mut parameter.value)
+            .ok_or_else(|| anyhow!("No binding named '{}' in the material", name))
+    }
+    pub fn set_texture(&mut self, name: &str, texture: TextureRef) -> anyhow::Result<()> {
+        match self.find_binding(name)? {
+            MaterialParameterValue::Texture(t) => *t = texture,
+            _ => bail!("'{}' isn't a texture binding", name),
+        }
+        self.bind_groups_dirty = true;
+        Ok(())
+    }
+    /// Binds the sampler of the given texture, see [`Texture::create_sampler`]
+    pub fn set_sampler(&mut self, name: &str, texture: TextureRef) -> anyhow::Result<()> {
+        match self.find_binding(name)? {
+            MaterialParameterValue::Sampler(t) => *t = texture,
+            _ => bail!("'{}' isn't a sampler binding", name),
+        }
+        self.bind_groups_dirty = true;
+        Ok(())
+    }
+
+    pub fn stencil(&self) -> &wgpu::StencilState { &self.stencil }
+    /// Changes the stencil test and operations of the material, which requires a depth format
+    /// with stencil, see [`RendererConfig::depth_format`]
+    ///
+    /// [`Renderer::edit_material`] fails and keeps the previous stencil otherwise.
+    pub fn set_stencil(&mut self, stencil: wgpu::StencilState) {
+        if self.stencil != stencil {
+            self.stencil = stencil;
+            self.pipelines_dirty = true;
+        }
+    }
+    /// Same test and operations for both faces, reading and writing all the bits of the stencil
+    pub fn set_stencil_ops(
+        &mut self, compare: wgpu::CompareFunction, fail_op: wgpu::StencilOperation,
+        depth_fail_op: wgpu::StencilOperation, pass_op: wgpu::StencilOperation,
+    ) {
+        let face = wgpu::StencilFaceState {
+            compare,
+            fail_op,
+            depth_fail_op,
+            pass_op,
+        };
+        self.set_stencil(wgpu::StencilState {
+            front: face,
+            back: face,
+            read_mask: 0xff,
+            write_mask: 0xff,
+        });
+    }
+    pub fn stencil_reference(&self) -> u32 { self.stencil_reference }
+    /// Value the stencil is compared to and written with by [`wgpu::StencilOperation::Replace`]
+    pub fn set_stencil_reference(&mut self, reference: u32) { self.stencil_reference = reference; }
+}
@@ -1,13 +1,248 @@
+use std::{borrow::Cow, ops::Range};
+
+use anyhow::bail;
+
 use super::*;
 
+/// Index data of a mesh
+#[derive(Debug, Clone, Copy)]
+pub enum MeshIndices<'a> {
+    U16(&'a [u16]),
+    U32(&'a [u32]),
+}
+impl<'a> From<&'a [u16]> for MeshIndices<'a> {
+    fn from(indices: &'a [u16]) -> Self { Self::U16(indices) }
+}
+impl<'a> From<&'a [u32]> for MeshIndices<'a> {
+    fn from(indices: &'a [u32]) -> Self { Self::U32(indices) }
+}
+impl<'a> MeshIndices<'a> {
+    pub fn len(&self) -> usize {
+        match self {
+            Self::U16(indices) => indices.len(),
+            Self::U32(indices) => indices.len(),
+        }
+    }
+    pub fn is_empty(&self) -> bool { self.len() == 0 }
+
+    /// Narrows 32 bits indices to 16 bits when they all fit, `0xFFFF` is kept free as it
+    /// restarts strips
+    pub fn compact(self) -> (wgpu::IndexFormat, Cow<'a, [u8]>) {
+        match self {
+            Self::U16(indices) => (
+                wgpu::IndexFormat::Uint16,
+                Cow::Borrowed(bytemuck::cast_slice(indices)),
+            ),
+            Self::U32(indices) if indices.iter().all(|&i| i < u16::MAX as u32) => (
+                wgpu::IndexFormat::Uint16,
+                Cow::Owned(
+                    indices
+                        .iter()
+                        .flat_map(|&i| (i as u16).to_ne_bytes())
+                        .collect(),
+                ),
+            ),
+            Self::U32(indices) => (
+                wgpu::IndexFormat::Uint32,
+                Cow::Borrowed(bytemuck::cast_slice(indices)),
+            ),
+        }
+    }
+}
+
+/// Primitive state of the pipelines a material needs to draw a mesh
+#[derive(Debug, Clone, Copy, Hash, Eq, PartialEq)]
+pub struct PipelineKey {
+    pub topology: wgpu::PrimitiveTopology,
+    /// Only set for strip topologies, see [`wgpu::PrimitiveState::strip_index_format`]
+    pub strip_index_format: Option<wgpu::IndexFormat>,
+}
+impl Default for PipelineKey {
+    fn default() -> Self {
+        Self {
+            topology: wgpu::PrimitiveTopology::TriangleList,
+            strip_index_format: None,
+        }
+    }
+}
+
+/// How often the geometry of a mesh is expected to change
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum MeshUsage {
+    /// Buffers are sized exactly and recreated on every update
+    Static,
+    /// Buffers are over-allocated and updated in place through the queue's staging memory
+    Dynamic,
+}
+
+/// Range of a mesh's indices drawn with the material of one of its slots
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Submesh {
+    pub indices: Range<u32>,
+    pub material_slot: usize,
+}
+impl Submesh {
+    /// Checks the submeshes against a mesh, no submeshes means a single one drawing every index
+    /// with the first slot
+    pub(crate) fn validate(
+        submeshes: &[Submesh], material_slots: usize, indices_size: usize,
+    ) -> anyhow::Result<SmallVec<[Submesh; 1]>> {
+        if submeshes.is_empty() {
+            return Ok(std::iter::once(Submesh {
+                indices: 0..indices_size as u32,
+                material_slot: 0,
+            })
+            .collect());
+        }
+        for submesh in submeshes {
+            if submesh.material_slot >= material_slots {
+                bail!(
+                    "Submesh uses material slot {} but the mesh has {} slots",
+                    submesh.material_slot,
+                    material_slots
+                );
+            }
+            if submesh.indices.start > submesh.indices.end
+                || submesh.indices.end as usize > indices_size
+            {
+                bail!(
+                    "Submesh indices {:?} are out of the mesh's {} indices",
+                    submesh.indices,
+                    indices_size
+                );
+            }
+        }
+        Ok(submeshes.into())
+    }
+}
+
 pub struct Mesh {
-    pub material: MaterialRef,
+    /// Default material of each slot, can be overridden by [`MeshComponent`]
+    pub materials: SmallVec<[MaterialRef; 1]>,
+    pub submeshes: SmallVec<[Submesh; 1]>,
+    pub usage: MeshUsage,
 
     pub vertex_buffers: SmallVec<[wgpu::Buffer; 2]>,
     pub indices: wgpu::Buffer,
     pub indices_size: usize,
+    pub index_format: wgpu::IndexFormat,
+    pub topology: wgpu::PrimitiveTopology,
+
+    /// Allocated size in bytes of each vertex buffer
+    pub(crate) vertex_buffer_capacities: SmallVec<[wgpu::BufferAddress; 2]>,
+    pub(crate) indices_capacity: wgpu::BufferAddress,
+}
+impl Mesh {
+    pub fn pipeline_key(&self) -> PipelineKey {
+        PipelineKey {
+            topology: self.topology,
+            strip_index_format: match self.topology {
+                wgpu::PrimitiveTopology::LineStrip | wgpu::PrimitiveTopology::TriangleStrip => {
+                    Some(self.index_format)
+                }
+                _ => None,
+            },
+        }
+    }
 }
 #[derive(Debug, Clone, Copy, Eq, PartialEq)]
 pub struct MeshRef(pub(crate) usize);
 
-pub struct MeshComponent(pub MeshRef);
+pub struct MeshComponent {
+    pub mesh: MeshRef,
+    /// Replaces the mesh's material of the slot at the same index, only written by
+    /// [`Self::set_material`] so that overrides are validated
+    material_overrides: SmallVec<[Option<MaterialRef>; 2]>,
+}
+impl MeshComponent {
+    pub fn new(mesh: MeshRef) -> Self {
+        Self {
+            mesh,
+            material_overrides: SmallVec::new(),
+        }
+    }
+    /// Overrides the material of a slot, see [`Self::set_material`]
+    pub fn with_material(
+        mut self, renderer: &Renderer, slot: usize, material: MaterialRef,
+    ) -> anyhow::Result<Self> {
+        self.set_material(renderer, slot, Some(material))?;
+        Ok(self)
+    }
+    /// Overrides the material of a slot, or restores the mesh's one with `None`
+    ///
+    /// Fails if the mesh has no such slot or if the material doesn't accept the mesh's vertex
+    /// layout, the override is then left unchanged.
+    pub fn set_material(
+        &mut self, renderer: &Renderer, slot: usize, material: Option<MaterialRef>,
+    ) -> anyhow::Result<()> {
+        if let Some(material) = material {
+            renderer.check_material_override(self.mesh, slot, material)?;
+        }
+        if self.material_overrides.len() <= slot {
+            self.material_overrides.resize(slot + 1, None);
+        }
+        self.material_overrides[slot] = material;
+        Ok(())
+    }
+
+    /// Material overriding each slot, `None` for the slots using the mesh's material
+    pub fn material_overrides(&self) -> &[Option<MaterialRef>] { &self.material_overrides }
+    /// Material used to draw the given slot of the mesh, `None` if the mesh has no such slot
+    pub fn material(&self, mesh: &Mesh, slot: usize) -> Option<MaterialRef> {
+        let default = *mesh.materials.get(slot)?;
+        Some(
+            self.material_overrides
+                .get(slot)
+                .copied()
+                .flatten()
+                .unwrap_or(default),
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn validate_submeshes() {
+        let whole = Submesh::validate(&[], 1, 12).unwrap();
+        assert_eq!(whole.as_slice(), &[Submesh {
+            indices: 0..12,
+            material_slot: 0,
+        }]);
+
+        let submeshes = [
+            Submesh {
+                indices: 0..6,
+                material_slot: 0,
+            },
+            Submesh {
+                indices: 6..12,
+                material_slot: 1,
+            },
+        ];
+        assert!(Submesh::validate(&submeshes, 2, 12).is_ok());
+        assert!(Submesh::validate(&submeshes, 1, 12).is_err());
+        assert!(Submesh::validate(&submeshes, 2, 9).is_err());
+    }
+
+    #[test]
+    fn compact_indices() {
+        let (format, bytes) = MeshIndices::from(&[0u32, 1, 65534][..]).compact();
+        assert_eq!(format, wgpu::IndexFormat::Uint16);
+        let indices = bytes
+            .chunks(2)
+            .map(|c| u16::from_ne_bytes([c[0], c[1]]))
+            .collect::<Vec<_>>();
+        assert_eq!(indices, &[0, 1, 65534]);
+
+        let (format, bytes) = MeshIndices::from(&[0u32, 65535][..]).compact();
+        assert_eq!(format, wgpu::IndexFormat::Uint32);
+        assert_eq!(bytes.len(), 8);
+
+        let (format, bytes) = MeshIndices::from(&[3u16, 2][..]).compact();
+        assert_eq!(format, wgpu::IndexFormat::Uint16);
+        assert_eq!(bytes.len(), 4);
+    }
+}
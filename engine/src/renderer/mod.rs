@@ -1,23 +1,43 @@
 mod material;
 mod mesh;
+mod preprocessor;
+mod profiler;
+mod reflection;
+mod render_graph;
 mod shader;
+mod stats;
 mod texture;
+mod vertex;
 
-use std::sync::{Mutex, RwLock};
+use std::{
+    borrow::Cow,
+    cell::RefCell,
+    collections::{HashMap, HashSet},
+    convert::TryInto,
+    num::NonZeroU64,
+    path::Path,
+    sync::{Mutex, MutexGuard, RwLock},
+};
 
+use anyhow::{bail, Context};
 use bytemuck::{Pod, Zeroable};
 use imgui_wgpu::Renderer as ImGuiRenderer;
 pub use material::*;
-use memoffset::offset_of;
 pub use mesh::*;
+pub use preprocessor::*;
+pub use profiler::*;
+pub use reflection::*;
+pub use render_graph::*;
 pub use shader::*;
 use smallvec::SmallVec;
+pub use stats::*;
 pub use texture::*;
+pub use vertex::*;
 use wgpu::util::DeviceExt;
 
 use crate::{
-    camera::CameraComponent,
-    transform::{get_global_transform, TransformComponent},
+    camera::{CameraComponent, ClipDepth},
+    transform::{GlobalTransformComponent, PreviousTransformComponent, TransformComponent},
 };
 
 #[derive(Copy, Clone, Zeroable, Pod)]
@@ -27,7 +47,73 @@ pub struct RenderUniformBuffer {
     pub model_matrix: [f32; 16],
 }
 
+/// Buffer holding one [`RenderUniformBuffer`] per drawn mesh, each bound with a dynamic offset
+struct RenderUniforms {
+    buffer: wgpu::Buffer,
+    bind_group: wgpu::BindGroup,
+    /// Number of [`RenderUniformBuffer`]s the buffer can hold
+    capacity: usize,
+}
+impl RenderUniforms {
+    /// Dynamic offsets must be aligned to [`wgpu::BIND_BUFFER_ALIGNMENT`]
+    const STRIDE: u64 = wgpu::BIND_BUFFER_ALIGNMENT;
+
+    fn new(device: &wgpu::Device, layout: &wgpu::BindGroupLayout, capacity: usize) -> Self {
+        let buffer = device.create_buffer(&wgpu::BufferDescriptor {
+            label: Some("Render uniforms"),
+            size: capacity as u64 * Self::STRIDE,
+            usage: wgpu::BufferUsage::UNIFORM | wgpu::BufferUsage::COPY_DST,
+            mapped_at_creation: false,
+        });
+        let bind_group = device.create_bind_group(&wgpu::BindGroupDescriptor {
+            label: None,
+            layout,
+            entries: &[wgpu::BindGroupEntry {
+                binding: 0,
+                resource: wgpu::BindingResource::Buffer(wgpu::BufferBinding {
+                    buffer: &buffer,
+                    offset: 0,
+                    size: NonZeroU64::new(std::mem::size_of::<RenderUniformBuffer>() as u64),
+                }),
+            }],
+        });
+        Self {
+            buffer,
+            bind_group,
+            capacity,
+        }
+    }
+
+    fn dynamic_offset(index: usize) -> wgpu::DynamicOffset {
+        (index as u64 * Self::STRIDE) as wgpu::DynamicOffset
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct RendererConfig {
+    /// Clears depth to 0 and keeps the fragments with the greatest depth, cameras are converted
+    /// to [`ClipDepth::OneToZero`]
+    ///
+    /// Best used with [`PerspectiveCameraMatrix::reversed_infinite`](crate::camera::PerspectiveCameraMatrix::reversed_infinite).
+    pub reverse_z: bool,
+    /// Format of the depth buffer, `Depth32Float`, `Depth24Plus` or `Depth24PlusStencil8` for
+    /// materials using the stencil
+    ///
+    /// `Depth32FloatStencil8` isn't available in wgpu 0.8, [`Renderer::new`] fails with any other
+    /// format.
+    pub depth_format: wgpu::TextureFormat,
+}
+impl Default for RendererConfig {
+    fn default() -> Self {
+        Self {
+            reverse_z: false,
+            depth_format: wgpu::TextureFormat::Depth32Float,
+        }
+    }
+}
+
 pub struct Renderer {
+    config: RendererConfig,
     surface: wgpu::Surface,
     pub device: wgpu::Device,
     pub queue: wgpu::Queue,
@@ -36,53 +122,45 @@ pub struct Renderer {
     swap_chain_format: wgpu::TextureFormat,
     swap_chain: wgpu::SwapChain,
 
-    depth_buffer_texture: Texture,
-
-    render_uniform_buffer: wgpu::Buffer,
     render_uniform_bind_group_layout: wgpu::BindGroupLayout,
-    render_uniform_bind_group: wgpu::BindGroup,
+    render_uniforms: Mutex<RenderUniforms>,
 
     shaders: RwLock<Vec<Shader>>,
+    /// Permutations compiled by [`Self::get_shader_permutation`], keyed by the base shader and sorted defines
+    shader_permutations: RwLock<HashMap<(ShaderRef, Vec<String>), ShaderRef>>,
     materials: RwLock<Vec<Material>>,
     meshes: RwLock<Vec<Mesh>>,
+    textures: RwLock<Vec<Texture>>,
 
     imgui_renderer: Mutex<ImGuiRenderer>,
+    profiler: Mutex<FrameProfiler>,
+    transient_textures: Mutex<TransientTexturePool>,
+    /// Counters of the last rendered frame, see [`Self::render_stats`]
+    frame_stats: Mutex<RenderStats>,
 }
 static_assertions::assert_impl_all!(Renderer: Send, Sync);
 
+thread_local! {
+    /// Errors of the wgpu calls made by [`Renderer::capture_errors`], innermost call last
+    #[allow(unknown_lints, clippy::missing_const_for_thread_local)]
+    static ERROR_SCOPES: RefCell<Vec<Vec<String>>> = RefCell::new(Vec::new());
+}
+
 impl Renderer {
     const VSYNC_PRESENT_MODE: wgpu::PresentMode = wgpu::PresentMode::Fifo;
-    const DEPTH_TEXTURE_FORMAT: wgpu::TextureFormat = wgpu::TextureFormat::Depth32Float;
-
-    fn create_depth_texture(device: &wgpu::Device, sc_desc: &wgpu::SwapChainDescriptor) -> Texture {
-        let size = wgpu::Extent3d {
-            width: sc_desc.width,
-            height: sc_desc.height,
-            depth_or_array_layers: 1,
-        };
-        let desc = wgpu::TextureDescriptor {
-            label: None,
-            size,
-            mip_level_count: 1,
-            sample_count: 1,
-            dimension: wgpu::TextureDimension::D2,
-            format: Self::DEPTH_TEXTURE_FORMAT,
-            usage: wgpu::TextureUsage::RENDER_ATTACHMENT | wgpu::TextureUsage::COPY_DST,
-        };
-        let texture = device.create_texture(&desc);
-
-        let view = texture.create_view(&wgpu::TextureViewDescriptor::default());
-
-        Texture {
-            texture,
-            view,
-            sampler: None,
-        }
-    }
 
     pub async fn new(
-        window: &winit::window::Window, width: u32, height: u32, imgui_context: &mut imgui::Context,
-    ) -> Renderer {
+        window: &winit::window::Window, width: u32, height: u32,
+        imgui_context: &mut imgui::Context, config: RendererConfig,
+    ) -> anyhow::Result<Renderer> {
+        if !matches!(
+            config.depth_format,
+            wgpu::TextureFormat::Depth32Float
+                | wgpu::TextureFormat::Depth24Plus
+                | wgpu::TextureFormat::Depth24PlusStencil8
+        ) {
+            bail!("{:?} isn't a supported depth format", config.depth_format);
+        }
         let instance = wgpu::Instance::new(wgpu::BackendBit::all());
         let surface = unsafe { instance.create_surface(window) };
         let adapter = instance
@@ -98,7 +176,8 @@ impl Renderer {
             .request_device(
                 &wgpu::DeviceDescriptor {
                     label: None,
-                    features: wgpu::Features::empty(),
+                    // Timestamps are only used for profiling so they are optional
+                    features: adapter.features() & wgpu::Features::TIMESTAMP_QUERY,
                     limits: wgpu::Limits::default(),
                 },
                 None,
@@ -106,6 +185,19 @@ impl Renderer {
             .await
             .expect("Failed to create device");
 
+        device.on_uncaptured_error(|error| {
+            let uncaptured = ERROR_SCOPES.with(|scopes| match scopes.borrow_mut().last_mut() {
+                Some(errors) => {
+                    errors.push(error.to_string());
+                    None
+                }
+                None => Some(error),
+            });
+            if let Some(error) = uncaptured {
+                panic!("wgpu error: {}", error);
+            }
+        });
+
         let swap_chain_format = adapter.get_swap_chain_preferred_format(&surface).unwrap();
         let swap_chain_descriptor = wgpu::SwapChainDescriptor {
             usage: wgpu::TextureUsage::RENDER_ATTACHMENT,
@@ -116,12 +208,6 @@ impl Renderer {
         };
         let swap_chain = device.create_swap_chain(&surface, &swap_chain_descriptor);
 
-        let render_uniform_buffer = device.create_buffer(&wgpu::BufferDescriptor {
-            label: Some("Vertex Buffer"),
-            size: std::mem::size_of::<RenderUniformBuffer>() as u64,
-            usage: wgpu::BufferUsage::UNIFORM | wgpu::BufferUsage::COPY_DST,
-            mapped_at_creation: false,
-        });
         let render_uniform_bind_group_layout =
             device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
                 label: None,
@@ -130,35 +216,30 @@ impl Renderer {
                     visibility: wgpu::ShaderStage::all(),
                     ty: wgpu::BindingType::Buffer {
                         ty: wgpu::BufferBindingType::Uniform,
-                        has_dynamic_offset: false,
-                        min_binding_size: None,
+                        has_dynamic_offset: true,
+                        min_binding_size: NonZeroU64::new(
+                            std::mem::size_of::<RenderUniformBuffer>() as u64,
+                        ),
                     },
                     count: None,
                 }],
             });
-        let render_uniform_bind_group = device.create_bind_group(&wgpu::BindGroupDescriptor {
-            label: None,
-            layout: &render_uniform_bind_group_layout,
-            entries: &[wgpu::BindGroupEntry {
-                binding: 0,
-                resource: wgpu::BindingResource::Buffer(
-                    render_uniform_buffer.as_entire_buffer_binding(),
-                ),
-            }],
-        });
+        let render_uniforms = RenderUniforms::new(&device, &render_uniform_bind_group_layout, 1);
 
-        Self {
-            depth_buffer_texture: Self::create_depth_texture(&device, &swap_chain_descriptor),
+        let renderer = Self {
             imgui_renderer: Mutex::new(ImGuiRenderer::new(
                 imgui_context,
                 &device,
                 &queue,
                 imgui_wgpu::RendererConfig {
                     texture_format: swap_chain_format,
-                    depth_format: Some(Self::DEPTH_TEXTURE_FORMAT),
+                    depth_format: Some(config.depth_format),
                     ..imgui_wgpu::RendererConfig::new()
                 },
             )),
+            profiler: Mutex::new(FrameProfiler::new(&device, &queue)),
+            frame_stats: Mutex::default(),
+            transient_textures: Mutex::default(),
 
             surface,
             device,
@@ -168,28 +249,86 @@ impl Renderer {
             swap_chain_format,
             swap_chain,
 
-            render_uniform_buffer,
             render_uniform_bind_group_layout,
-            render_uniform_bind_group,
+            render_uniforms: Mutex::new(render_uniforms),
 
             materials: RwLock::default(),
             shaders: RwLock::default(),
+            shader_permutations: RwLock::default(),
             meshes: RwLock::default(),
-        }
+            textures: RwLock::default(),
+
+            config,
+        };
+
+        let mut default_texture = Texture::create_plain_color_texture(
+            &renderer,
+            image::Rgba([255, 255, 255, 255]),
+            Some("Default texture"),
+        );
+        default_texture.create_sampler(
+            &renderer,
+            wgpu::AddressMode::Repeat,
+            wgpu::FilterMode::Linear,
+            wgpu::FilterMode::Linear,
+        );
+        renderer.add_texture(default_texture);
+
+        Ok(renderer)
+    }
+    /// Runs f, collecting every wgpu error it raises instead of panicking
+    ///
+    /// wgpu 0.8 has no error scopes but reports errors on the thread that made the failing
+    /// call, so scopes are emulated per thread and calls made by other threads are unaffected.
+    fn capture_errors<R>(&self, f: impl FnOnce() -> R) -> (R, Vec<String>) {
+        ERROR_SCOPES.with(|scopes| scopes.borrow_mut().push(Vec::new()));
+        let result = f();
+        let errors = ERROR_SCOPES.with(|scopes| scopes.borrow_mut().pop().unwrap());
+        (result, errors)
     }
     fn recreate_swap_chain(&mut self) {
         self.swap_chain = self
             .device
             .create_swap_chain(&self.surface, &self.swap_chain_descriptor);
-
-        self.depth_buffer_texture =
-            Self::create_depth_texture(&self.device, &self.swap_chain_descriptor);
     }
 
-    pub fn resize(&mut self, width: u32, height: u32) {
+    /// Resizes the swap chain and updates the aspect ratio of the world's cameras
+    pub fn resize(&mut self, width: u32, height: u32, world: &hecs::World) {
         self.swap_chain_descriptor.width = width;
         self.swap_chain_descriptor.height = height;
         self.recreate_swap_chain();
+
+        let aspect = self.aspect_ratio();
+        for (_, camera) in world.query::<&mut CameraComponent>().iter() {
+            camera.matrix.set_aspect(aspect);
+        }
+    }
+    /// Width / height of the swap chain
+    pub fn aspect_ratio(&self) -> f32 {
+        self.swap_chain_descriptor.width as f32 / self.swap_chain_descriptor.height as f32
+    }
+    pub fn config(&self) -> &RendererConfig { &self.config }
+    /// Whether the depth buffer has a stencil that materials can use
+    pub fn has_stencil(&self) -> bool {
+        self.config.depth_format == wgpu::TextureFormat::Depth24PlusStencil8
+    }
+    fn check_stencil(&self, stencil: &wgpu::StencilState) -> anyhow::Result<()> {
+        if stencil.is_enabled() && !self.has_stencil() {
+            bail!(
+                "Materials can only use the stencil with a depth format that has one, not {:?}",
+                self.config.depth_format
+            );
+        }
+        Ok(())
+    }
+    /// Depth convention of the depth buffer, camera projections are converted to it
+    pub fn clip_depth(&self) -> ClipDepth {
+        if self.config.reverse_z {
+            ClipDepth::OneToZero
+        }
+        else {
+            ClipDepth::ZeroToOne
+        }
     }
     pub fn set_vsync(&mut self, enabled: bool) {
         if self.get_vsync() == enabled {
@@ -207,160 +346,933 @@ impl Renderer {
         self.swap_chain_descriptor.present_mode == Self::VSYNC_PRESENT_MODE
     }
 
+    /// Checks that the shader's group 0 matches [`RenderUniformBuffer`]
+    fn validate_render_uniforms(reflection: &ShaderReflection) -> anyhow::Result<()> {
+        for binding in reflection.bind_groups.first().into_iter().flatten() {
+            match binding.ty {
+                wgpu::BindingType::Buffer {
+                    ty: wgpu::BufferBindingType::Uniform,
+                    min_binding_size,
+                    ..
+                } if binding.binding == 0
+                    && min_binding_size.map_or(0, |s| s.get())
+                        <= std::mem::size_of::<RenderUniformBuffer>() as u64 => {}
+                _ => bail!(
+                    "Group 0 is reserved for the render uniforms, found '{}' at binding {}",
+                    binding.name.as_deref().unwrap_or("?"),
+                    binding.binding
+                ),
+            }
+        }
+        Ok(())
+    }
+    /// Preprocesses a WGSL source containing both the `vertex` and `fragment` stages and reflects
+    /// its bind group and vertex layouts. If no vertex layouts are given, each vertex input gets
+    /// its own buffer
+    fn compile_shader(
+        &self, label: &str, source: String, defines: Vec<String>,
+        vertex_layouts: Option<&[VertexLayout]>,
+    ) -> anyhow::Result<Shader> {
+        let preprocessed_source = preprocess_wgsl(&source, &defines)?;
+        let reflection = ShaderReflection::from_wgsl(&preprocessed_source)?;
+        Self::validate_render_uniforms(&reflection)?;
+        let vertex_layouts: SmallVec<_> = match vertex_layouts {
+            Some(layouts) => {
+                reflection.validate_vertex_layouts(layouts)?;
+                layouts.into()
+            }
+            None => reflection.separate_vertex_layouts(),
+        };
+
+        let ((module, bind_group_layouts, render_pipeline_layout), errors) =
+            self.capture_errors(|| {
+                let module = self
+                    .device
+                    .create_shader_module(&wgpu::ShaderModuleDescriptor {
+                        label: Some(label),
+                        source: wgpu::ShaderSource::Wgsl(Cow::Owned(preprocessed_source)),
+                        flags: Default::default(),
+                    });
+                let (bind_group_layouts, render_pipeline_layout) =
+                    self.create_shader_layouts(&reflection);
+                (module, bind_group_layouts, render_pipeline_layout)
+            });
+        if !errors.is_empty() {
+            bail!("{}", errors.join("\n"));
+        }
+
+        Ok(Shader {
+            module,
+            render_pipeline_layout,
+            bind_group_layouts,
+            vertex_layouts,
+            reflection,
+            source,
+            defines,
+            source_file: None,
+
+            marker: Default::default(),
+        })
+    }
+    /// Creates a shader from a WGSL source containing both the `vertex` and `fragment` stages,
+    /// see [`Self::compile_shader`]
     pub fn create_shader(
-        &self, vertex_shader_module: &wgpu::ShaderModuleDescriptor,
-        fragment_shader_module: &wgpu::ShaderModuleDescriptor,
-        bind_group_layouts: &[&wgpu::BindGroupLayoutDescriptor],
-        vertex_buffer_layouts: &[wgpu::VertexBufferLayout<'static>],
-    ) -> ShaderRef {
+        &self, label: &str, source: &str, vertex_layouts: Option<&[VertexLayout]>,
+    ) -> anyhow::Result<ShaderRef> {
+        let shader = self
+            .compile_shader(label, source.to_owned(), Vec::new(), vertex_layouts)
+            .with_context(|| format!("Failed to create shader {}", label))?;
+
         let mut shaders = self.shaders.write().unwrap();
+        shaders.push(shader);
+        Ok(ShaderRef(shaders.len() - 1))
+    }
+    /// Same as [`Self::create_shader`] but the source is read from a file
+    /// which is watched by [`Self::reload_modified_shaders`]
+    pub fn create_shader_from_file(
+        &self, path: &Path, vertex_layouts: Option<&[VertexLayout]>,
+    ) -> anyhow::Result<ShaderRef> {
+        let label = path.to_string_lossy();
+        let source_file = ShaderSourceFile::new(path.to_owned());
+        let mut shader = source_file
+            .read_source()
+            .and_then(|source| self.compile_shader(&label, source, Vec::new(), vertex_layouts))
+            .with_context(|| format!("Failed to load shader {}", label))?;
+        shader.source_file = Some(source_file);
 
-        let i = shaders.len();
-        let bind_group_layouts: SmallVec<_> = bind_group_layouts
-            .iter()
-            .map(|desc| self.device.create_bind_group_layout(desc))
-            .collect();
+        let mut shaders = self.shaders.write().unwrap();
+        shaders.push(shader);
+        Ok(ShaderRef(shaders.len() - 1))
+    }
+    /// Returns the variant of a shader compiled with the given preprocessor defines,
+    /// compiling it the first time it is requested.
+    /// It uses the same vertex layouts as the base shader so that meshes are compatible with all
+    /// its permutations
+    pub fn get_shader_permutation(
+        &self, base_ref: ShaderRef, defines: &[&str],
+    ) -> anyhow::Result<ShaderRef> {
+        let mut defines: Vec<String> = defines.iter().map(|d| d.to_string()).collect();
+        defines.sort();
+        defines.dedup();
+        if defines.is_empty() {
+            return Ok(base_ref);
+        }
+        let key = (base_ref, defines);
+        if let Some(&shader_ref) = self.shader_permutations.read().unwrap().get(&key) {
+            return Ok(shader_ref);
+        }
 
-        shaders.push(Shader {
-            vertex_shader_module: self.device.create_shader_module(vertex_shader_module),
-            fragment_shader_module: self.device.create_shader_module(fragment_shader_module),
-            render_pipeline_layout: self.device.create_pipeline_layout(
-                &wgpu::PipelineLayoutDescriptor {
+        let (label, source, vertex_layouts, source_file) = {
+            let shaders = self.shaders.read().unwrap();
+            let base = &shaders[base_ref.0];
+            let label = match &base.source_file {
+                Some(source_file) => source_file.path.to_string_lossy().into_owned(),
+                None => format!("shader {}", base_ref.0),
+            };
+            (
+                format!("{} ({})", label, key.1.join(", ")),
+                base.source.clone(),
+                base.vertex_layouts.clone(),
+                base.source_file.clone(),
+            )
+        };
+        let mut shader = self
+            .compile_shader(&label, source, key.1.clone(), Some(&vertex_layouts))
+            .with_context(|| format!("Failed to create {}", label))?;
+        shader.source_file = source_file;
+
+        let mut permutations = self.shader_permutations.write().unwrap();
+        if let Some(&shader_ref) = permutations.get(&key) {
+            return Ok(shader_ref);
+        }
+        let mut shaders = self.shaders.write().unwrap();
+        shaders.push(shader);
+        let shader_ref = ShaderRef(shaders.len() - 1);
+        permutations.insert(key, shader_ref);
+        Ok(shader_ref)
+    }
+    /// Layouts of the bind groups following the render uniforms and the pipeline layout using them
+    fn create_shader_layouts(
+        &self, reflection: &ShaderReflection,
+    ) -> (SmallVec<[wgpu::BindGroupLayout; 2]>, wgpu::PipelineLayout) {
+        let bind_group_layouts: SmallVec<[_; 2]> = (1..reflection.bind_groups.len())
+            .map(|group| {
+                self.device
+                    .create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
+                        label: None,
+                        entries: &reflection.bind_group_layout_entries(group),
+                    })
+            })
+            .collect();
+        let render_pipeline_layout =
+            self.device
+                .create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
                     label: None,
                     bind_group_layouts: std::iter::once(&self.render_uniform_bind_group_layout)
                         .chain(bind_group_layouts.iter())
                         .collect::<Vec<_>>()
                         .as_slice(),
                     push_constant_ranges: &[],
-                },
-            ),
-            bind_group_layouts,
-            vertex_group_layouts: vertex_buffer_layouts.into(),
+                });
+        (bind_group_layouts, render_pipeline_layout)
+    }
+    /// Recompiles every file shader whose source changed since it was last loaded,
+    /// and rebuilds the pipelines of the materials using it.
+    /// On failure the previous version is kept and the error is returned
+    pub fn reload_modified_shaders(&self) -> Vec<anyhow::Error> {
+        let mut shaders = self.shaders.write().unwrap();
+        let mut materials = self.materials.write().unwrap();
+        let mut errors = Vec::new();
 
-            marker: Default::default(),
-        });
+        for (i, shader) in shaders.iter_mut().enumerate() {
+            let source_file = match &mut shader.source_file {
+                Some(source_file) => source_file,
+                None => continue,
+            };
+            if !source_file.poll_modified() {
+                continue;
+            }
+            let path = source_file.path.to_string_lossy().into_owned();
+            if let Err(error) = self.reload_shader(ShaderRef(i), shader, &mut materials) {
+                errors.push(error.context(format!("Failed to reload shader {}", path)));
+            }
+        }
 
-        ShaderRef(i)
+        errors
     }
-    pub fn create_material(
-        &self, shader_ref: ShaderRef, bind_groups: &[&[wgpu::BindGroupEntry]],
-        cull_mode: Option<wgpu::Face>,
-    ) -> MaterialRef {
-        let shaders = self.shaders.read().unwrap();
-        let mut materials = self.materials.write().unwrap();
+    fn reload_shader(
+        &self, shader_ref: ShaderRef, shader: &mut Shader, materials: &mut [Material],
+    ) -> anyhow::Result<()> {
+        let source_file = shader.source_file.as_ref().unwrap();
+        let source = source_file.read_source()?;
+        let preprocessed_source = preprocess_wgsl(&source, &shader.defines)?;
+        let reflection = ShaderReflection::from_wgsl(&preprocessed_source)?;
+        if !reflection.has_compatible_bind_groups(&shader.reflection) {
+            bail!("The bind groups changed, materials would have to be recreated");
+        }
+        reflection.validate_vertex_layouts(&shader.vertex_layouts)?;
+        // Only the stages using some bindings changed, the materials' bind groups are recreated
+        // with the new layouts
+        let layouts_changed = reflection.bind_groups != shader.reflection.bind_groups;
+        for material in materials
+            .iter()
+            .filter(|material| material.shader == shader_ref)
+        {
+            self.check_stencil(&material.stencil)?;
+        }
 
-        let i = materials.len();
-        let shader = &shaders[shader_ref.0];
-        materials.push(Material {
-            render_pipeline: self
+        let ((module, layouts, bind_groups, pipelines), errors) = self.capture_errors(|| {
+            let module = self
                 .device
-                .create_render_pipeline(&wgpu::RenderPipelineDescriptor {
-                    label: None,
-                    layout: Some(&shader.render_pipeline_layout),
-                    vertex: wgpu::VertexState {
-                        module: &shader.vertex_shader_module,
-                        entry_point: "vertex",
-                        buffers: shader.vertex_group_layouts.as_ref(),
-                    },
-                    fragment: Some(wgpu::FragmentState {
-                        module: &shader.fragment_shader_module,
-                        entry_point: "fragment",
-                        targets: &[self.swap_chain_format.into()],
-                    }),
-                    primitive: wgpu::PrimitiveState {
-                        topology: wgpu::PrimitiveTopology::TriangleList,
-                        strip_index_format: None,
-                        front_face: wgpu::FrontFace::Cw,
-                        cull_mode,
-                        clamp_depth: false,
-                        polygon_mode: wgpu::PolygonMode::Fill,
-                        conservative: false,
-                    },
-                    depth_stencil: Some(wgpu::DepthStencilState {
-                        format: wgpu::TextureFormat::Depth32Float,
-                        depth_write_enabled: true,
-                        depth_compare: wgpu::CompareFunction::Less,
-                        stencil: wgpu::StencilState::default(),
-                        bias: wgpu::DepthBiasState::default(),
-                    }),
-                    multisample: Default::default(),
-                }),
-            bind_groups: bind_groups
+                .create_shader_module(&wgpu::ShaderModuleDescriptor {
+                    label: Some(&source_file.path.to_string_lossy()),
+                    source: wgpu::ShaderSource::Wgsl(Cow::Owned(preprocessed_source)),
+                    flags: Default::default(),
+                });
+            let layouts = if layouts_changed {
+                Some(self.create_shader_layouts(&reflection))
+            }
+            else {
+                None
+            };
+            let (bind_group_layouts, render_pipeline_layout) = match &layouts {
+                Some((bind_group_layouts, render_pipeline_layout)) => {
+                    (bind_group_layouts, render_pipeline_layout)
+                }
+                None => (&shader.bind_group_layouts, &shader.render_pipeline_layout),
+            };
+            let bind_groups = materials
                 .iter()
                 .enumerate()
-                .map(|(i, entries)| {
-                    self.device.create_bind_group(&wgpu::BindGroupDescriptor {
-                        label: None,
-                        layout: &shader.bind_group_layouts[i],
-                        entries,
-                    })
+                .filter(|(_, material)| layouts_changed && material.shader == shader_ref)
+                .map(|(i, material)| {
+                    let bind_groups =
+                        self.create_material_bind_groups(bind_group_layouts, &material.parameters);
+                    (i, bind_groups)
                 })
-                .collect(),
+                .collect::<Vec<_>>();
+            let pipelines = materials
+                .iter()
+                .enumerate()
+                .filter(|(_, material)| material.shader == shader_ref)
+                .flat_map(|(i, material)| {
+                    material
+                        .render_pipelines
+                        .keys()
+                        .map(move |&key| (i, key, material))
+                })
+                .map(|(i, key, material)| {
+                    let pipeline = self.create_render_pipeline(
+                        render_pipeline_layout,
+                        &module,
+                        &shader.vertex_layouts,
+                        key,
+                        material.cull_mode,
+                        &material.stencil,
+                    );
+                    (i, key, pipeline)
+                })
+                .collect::<Vec<_>>();
+            (module, layouts, bind_groups, pipelines)
+        });
+        if !errors.is_empty() {
+            bail!("{}", errors.join("\n"));
+        }
+
+        if let Some((bind_group_layouts, render_pipeline_layout)) = layouts {
+            shader.bind_group_layouts = bind_group_layouts;
+            shader.render_pipeline_layout = render_pipeline_layout;
+        }
+        for (i, bind_groups) in bind_groups {
+            materials[i].bind_groups = bind_groups;
+            materials[i].bind_groups_dirty = false;
+        }
+        shader.module = module;
+        shader.reflection = reflection;
+        shader.source = source;
+        for (i, key, pipeline) in pipelines {
+            materials[i].render_pipelines.insert(key, pipeline);
+        }
+        Ok(())
+    }
+    fn create_render_pipeline(
+        &self, layout: &wgpu::PipelineLayout, module: &wgpu::ShaderModule,
+        vertex_layouts: &[VertexLayout], key: PipelineKey, cull_mode: Option<wgpu::Face>,
+        stencil: &wgpu::StencilState,
+    ) -> wgpu::RenderPipeline {
+        self.device
+            .create_render_pipeline(&wgpu::RenderPipelineDescriptor {
+                label: None,
+                layout: Some(layout),
+                vertex: wgpu::VertexState {
+                    module,
+                    entry_point: ShaderReflection::VERTEX_ENTRY_POINT,
+                    buffers: &vertex_layouts
+                        .iter()
+                        .map(VertexLayout::as_wgpu)
+                        .collect::<Vec<_>>(),
+                },
+                fragment: Some(wgpu::FragmentState {
+                    module,
+                    entry_point: ShaderReflection::FRAGMENT_ENTRY_POINT,
+                    targets: &[self.swap_chain_format.into()],
+                }),
+                primitive: wgpu::PrimitiveState {
+                    topology: key.topology,
+                    strip_index_format: key.strip_index_format,
+                    front_face: wgpu::FrontFace::Cw,
+                    cull_mode,
+                    clamp_depth: false,
+                    polygon_mode: wgpu::PolygonMode::Fill,
+                    conservative: false,
+                },
+                depth_stencil: Some(wgpu::DepthStencilState {
+                    format: self.config.depth_format,
+                    depth_write_enabled: true,
+                    depth_compare: self.clip_depth().depth_compare(),
+                    stencil: stencil.clone(),
+                    bias: wgpu::DepthBiasState::default(),
+                }),
+                multisample: Default::default(),
+            })
+    }
+    pub fn add_texture(&self, texture: Texture) -> TextureRef {
+        let mut textures = self.textures.write().unwrap();
+        textures.push(texture);
+        TextureRef(textures.len() - 1)
+    }
+    /// Plain white texture with a linear repeating sampler,
+    /// bound to the texture and sampler parameters of new materials
+    pub fn default_texture(&self) -> TextureRef { TextureRef(0) }
+
+    /// Creates a material whose parameters are initialized to zeroed uniforms and the
+    /// [default texture](Self::default_texture), they can then be changed with [`Self::edit_material`]
+    pub fn create_material(
+        &self, shader_ref: ShaderRef, cull_mode: Option<wgpu::Face>,
+    ) -> anyhow::Result<MaterialRef> {
+        let shaders = self.shaders.read().unwrap();
+        let shader = &shaders[shader_ref.0];
+
+        let parameters = shader
+            .reflection
+            .bind_groups
+            .iter()
+            .skip(1)
+            .map(|group| {
+                group
+                    .iter()
+                    .map(|binding| {
+                        let value = match binding.ty {
+                            wgpu::BindingType::Buffer {
+                                ty: wgpu::BufferBindingType::Uniform,
+                                min_binding_size,
+                                ..
+                            } => {
+                                // Keeps the buffer size a multiple of the largest uniform alignment
+                                let size = (min_binding_size.map_or(0, |s| s.get()) + 15) & !15;
+                                MaterialParameterValue::Uniform {
+                                    data: vec![0; size as usize],
+                                    buffer: self.device.create_buffer(&wgpu::BufferDescriptor {
+                                        label: binding.name.as_deref(),
+                                        size,
+                                        usage: wgpu::BufferUsage::UNIFORM
+                                            | wgpu::BufferUsage::COPY_DST,
+                                        mapped_at_creation: false,
+                                    }),
+                                    dirty: false,
+                                }
+                            }
+                            wgpu::BindingType::Texture {
+                                sample_type: wgpu::TextureSampleType::Float { .. },
+                                view_dimension: wgpu::TextureViewDimension::D2,
+                                multisampled: false,
+                            } => MaterialParameterValue::Texture(self.default_texture()),
+                            wgpu::BindingType::Sampler {
+                                comparison: false, ..
+                            } => MaterialParameterValue::Sampler(self.default_texture()),
+                            _ => bail!(
+                                "Binding '{}' can't be set by materials",
+                                binding.name.as_deref().unwrap_or("?")
+                            ),
+                        };
+                        Ok(MaterialParameter {
+                            reflection: binding.clone(),
+                            value,
+                        })
+                    })
+                    .collect::<anyhow::Result<_>>()
+            })
+            .collect::<anyhow::Result<SmallVec<_>>>()?;
+
+        let mut materials = self.materials.write().unwrap();
+        materials.push(Material {
+            render_pipelines: std::iter::once((
+                PipelineKey::default(),
+                self.create_render_pipeline(
+                    &shader.render_pipeline_layout,
+                    &shader.module,
+                    &shader.vertex_layouts,
+                    PipelineKey::default(),
+                    cull_mode,
+                    &wgpu::StencilState::default(),
+                ),
+            ))
+            .collect(),
+            bind_groups: self.create_material_bind_groups(&shader.bind_group_layouts, &parameters),
+            cull_mode,
+            stencil: wgpu::StencilState::default(),
+            stencil_reference: 0,
+            pipelines_dirty: false,
             shader: shader_ref,
+            parameters,
+            bind_groups_dirty: false,
 
             marker: Default::default(),
         });
 
-        MaterialRef(i)
+        Ok(MaterialRef(materials.len() - 1))
     }
-    pub fn create_mesh<T: Pod>(
-        &self, material: MaterialRef, indices: &[u32], vertex_buffers: &[&[T]],
-    ) -> MeshRef {
-        let mut meshes = self.meshes.write().unwrap();
-
-        let i = meshes.len();
-        meshes.push(Mesh {
-            material,
-            vertex_buffers: vertex_buffers
+    /// Creates the material's pipeline for the given primitive state if it doesn't exist yet
+    fn prepare_material_pipeline(
+        &self, material_ref: MaterialRef, key: PipelineKey,
+    ) -> anyhow::Result<()> {
+        let shaders = self.shaders.read().unwrap();
+        let mut materials = self.materials.write().unwrap();
+        let material = &mut materials[material_ref.0];
+        if material.render_pipelines.contains_key(&key) {
+            return Ok(());
+        }
+        self.check_stencil(&material.stencil)?;
+        let shader = &shaders[material.shader.0];
+        let (pipeline, errors) = self.capture_errors(|| {
+            self.create_render_pipeline(
+                &shader.render_pipeline_layout,
+                &shader.module,
+                &shader.vertex_layouts,
+                key,
+                material.cull_mode,
+                &material.stencil,
+            )
+        });
+        if !errors.is_empty() {
+            bail!("{}", errors.join("\n"));
+        }
+        material.render_pipelines.insert(key, pipeline);
+        Ok(())
+    }
+    /// Checks that the material can replace the mesh's own for the slot and creates the pipeline
+    /// it needs, see [`MeshComponent::set_material`]
+    pub(crate) fn check_material_override(
+        &self, mesh_ref: MeshRef, slot: usize, material: MaterialRef,
+    ) -> anyhow::Result<()> {
+        let (slot_material, key) = {
+            let meshes = self.meshes.read().unwrap();
+            let mesh = &meshes[mesh_ref.0];
+            match mesh.materials.get(slot) {
+                Some(&slot_material) => (slot_material, mesh.pipeline_key()),
+                None => bail!(
+                    "Material slot {} is out of the mesh's {} slots",
+                    slot,
+                    mesh.materials.len()
+                ),
+            }
+        };
+        {
+            let shaders = self.shaders.read().unwrap();
+            let materials = self.materials.read().unwrap();
+            let vertex_layouts = |material: MaterialRef| {
+                shaders[materials[material.0].shader.0]
+                    .vertex_layouts
+                    .as_slice()
+            };
+            if vertex_layouts(material) != vertex_layouts(slot_material) {
+                bail!(
+                    "The vertex layouts of the material don't match the mesh, expected {:?} but \
+                     got {:?}",
+                    vertex_layouts(slot_material),
+                    vertex_layouts(material)
+                );
+            }
+        }
+        self.prepare_material_pipeline(material, key)
+    }
+    /// Material overrides may need pipelines for primitive states their material wasn't used
+    /// with yet, the submeshes whose pipeline can't be created aren't drawn
+    fn prepare_override_pipelines(&self, world: &hecs::World) {
+        let missing = {
+            let meshes = self.meshes.read().unwrap();
+            let materials = self.materials.read().unwrap();
+            world
+                .query::<&MeshComponent>()
                 .iter()
-                .map(|vertices| {
-                    self.device
-                        .create_buffer_init(&wgpu::util::BufferInitDescriptor {
-                            label: None,
-                            contents: bytemuck::cast_slice(vertices),
-                            usage: wgpu::BufferUsage::VERTEX,
-                        })
+                .flat_map(|(_, component)| {
+                    let key = meshes[component.mesh.0].pipeline_key();
+                    component
+                        .material_overrides()
+                        .iter()
+                        .flatten()
+                        .map(move |&material| (material, key))
                 })
-                .collect(),
-            indices: self
-                .device
-                .create_buffer_init(&wgpu::util::BufferInitDescriptor {
+                .filter(|(material, key)| !materials[material.0].render_pipelines.contains_key(key))
+                .collect::<HashSet<_>>()
+        };
+        for (material, key) in missing {
+            // Overrides are validated by MeshComponent::set_material, pipelines can only be
+            // missing here when the mesh's primitive state changed since
+            let _ = self.prepare_material_pipeline(material, key);
+        }
+    }
+    fn create_material_bind_groups(
+        &self, layouts: &[wgpu::BindGroupLayout], parameters: &[SmallVec<[MaterialParameter; 4]>],
+    ) -> SmallVec<[wgpu::BindGroup; 2]> {
+        let textures = self.textures.read().unwrap();
+        let default_sampler = textures[self.default_texture().0].sampler.as_ref().unwrap();
+
+        layouts
+            .iter()
+            .zip(parameters)
+            .map(|(layout, parameters)| {
+                let entries = parameters
+                    .iter()
+                    .map(|parameter| wgpu::BindGroupEntry {
+                        binding: parameter.reflection.binding,
+                        resource: match &parameter.value {
+                            MaterialParameterValue::Uniform { buffer, .. } => {
+                                wgpu::BindingResource::Buffer(buffer.as_entire_buffer_binding())
+                            }
+                            MaterialParameterValue::Texture(texture) => {
+                                wgpu::BindingResource::TextureView(&textures[texture.0].view)
+                            }
+                            MaterialParameterValue::Sampler(texture) => {
+                                wgpu::BindingResource::Sampler(
+                                    textures[texture.0]
+                                        .sampler
+                                        .as_ref()
+                                        .unwrap_or(default_sampler),
+                                )
+                            }
+                        },
+                    })
+                    .collect::<Vec<_>>();
+                self.device.create_bind_group(&wgpu::BindGroupDescriptor {
                     label: None,
-                    contents: bytemuck::cast_slice(indices),
-                    usage: wgpu::BufferUsage::INDEX,
-                }),
-            indices_size: indices.len(),
-        });
+                    layout,
+                    entries: &entries,
+                })
+            })
+            .collect()
+    }
+    /// Gives mutable access to a material to change its parameters,
+    /// the uniform buffers and bind groups are updated when f returns
+    ///
+    /// Fails if the material uses the stencil without a depth format that has one or if its
+    /// pipelines can't be recreated, the previous stencil, cull mode and pipelines are then kept.
+    pub fn edit_material<R>(
+        &self, material_ref: MaterialRef, f: impl FnOnce(&mut Material) -> R,
+    ) -> anyhow::Result<R> {
+        let shaders = self.shaders.read().unwrap();
+        let mut materials = self.materials.write().unwrap();
+        let material = &mut materials[material_ref.0];
 
-        MeshRef(i)
+        let previous_stencil = material.stencil.clone();
+        let previous_cull_mode = material.cull_mode;
+        let result = f(material);
+
+        for parameter in material.parameters.iter_mut().flatten() {
+            if let MaterialParameterValue::Uniform {
+                data,
+                buffer,
+                dirty: dirty @ true,
+            } = &mut parameter.value
+            {
+                self.queue.write_buffer(buffer, 0, data);
+                *dirty = false;
+            }
+        }
+        if material.bind_groups_dirty {
+            material.bind_groups = self.create_material_bind_groups(
+                &shaders[material.shader.0].bind_group_layouts,
+                &material.parameters,
+            );
+            material.bind_groups_dirty = false;
+        }
+        if material.pipelines_dirty {
+            if let Err(error) = self.check_stencil(&material.stencil) {
+                material.stencil = previous_stencil;
+                material.pipelines_dirty = false;
+                return Err(error);
+            }
+            let shader = &shaders[material.shader.0];
+            let (render_pipelines, errors) = self.capture_errors(|| {
+                material
+                    .render_pipelines
+                    .keys()
+                    .map(|&key| {
+                        let pipeline = self.create_render_pipeline(
+                            &shader.render_pipeline_layout,
+                            &shader.module,
+                            &shader.vertex_layouts,
+                            key,
+                            material.cull_mode,
+                            &material.stencil,
+                        );
+                        (key, pipeline)
+                    })
+                    .collect::<HashMap<_, _>>()
+            });
+            material.pipelines_dirty = false;
+            if !errors.is_empty() {
+                material.stencil = previous_stencil;
+                material.cull_mode = previous_cull_mode;
+                bail!("{}", errors.join("\n"));
+            }
+            material.render_pipelines = render_pipelines;
+        }
+
+        Ok(result)
+    }
+    fn create_mesh_buffer(
+        &self, contents: &[u8], usage: wgpu::BufferUsage, mesh_usage: MeshUsage,
+    ) -> (wgpu::Buffer, wgpu::BufferAddress) {
+        match mesh_usage {
+            MeshUsage::Static => {
+                let buffer = self
+                    .device
+                    .create_buffer_init(&wgpu::util::BufferInitDescriptor {
+                        label: None,
+                        contents,
+                        usage,
+                    });
+                (buffer, contents.len() as wgpu::BufferAddress)
+            }
+            MeshUsage::Dynamic => {
+                // Leaves room to grow so most updates don't need a new buffer
+                let capacity = (contents.len() as wgpu::BufferAddress)
+                    .next_power_of_two()
+                    .max(wgpu::COPY_BUFFER_ALIGNMENT);
+                let buffer = self.device.create_buffer(&wgpu::BufferDescriptor {
+                    label: None,
+                    size: capacity,
+                    usage: usage | wgpu::BufferUsage::COPY_DST,
+                    mapped_at_creation: false,
+                });
+                self.write_mesh_buffer(&buffer, contents);
+                (buffer, capacity)
+            }
+        }
+    }
+    fn write_mesh_buffer(&self, buffer: &wgpu::Buffer, contents: &[u8]) {
+        // Buffer writes have to be a multiple of COPY_BUFFER_ALIGNMENT
+        let padding = (wgpu::COPY_BUFFER_ALIGNMENT as usize
+            - contents.len() % wgpu::COPY_BUFFER_ALIGNMENT as usize)
+            % wgpu::COPY_BUFFER_ALIGNMENT as usize;
+        if padding == 0 {
+            self.queue.write_buffer(buffer, 0, contents);
+        }
+        else {
+            let mut padded = Vec::with_capacity(contents.len() + padding);
+            padded.extend_from_slice(contents);
+            padded.resize(contents.len() + padding, 0);
+            self.queue.write_buffer(buffer, 0, &padded);
+        }
+    }
+    /// Writes into a mesh buffer, recreating it if it is static or too small
+    fn update_mesh_buffer(
+        &self, buffer: &mut wgpu::Buffer, capacity: &mut wgpu::BufferAddress, contents: &[u8],
+        usage: wgpu::BufferUsage, mesh_usage: MeshUsage,
+    ) {
+        if mesh_usage == MeshUsage::Dynamic && contents.len() as wgpu::BufferAddress <= *capacity {
+            self.write_mesh_buffer(buffer, contents);
+        }
+        else {
+            let (new_buffer, new_capacity) = self.create_mesh_buffer(contents, usage, mesh_usage);
+            *buffer = new_buffer;
+            *capacity = new_capacity;
+        }
     }
 
-    pub fn render(&self, imgui_draw_data: &imgui::DrawData, world: &hecs::World) {
-        let mut query = world.query::<(&CameraComponent, &TransformComponent)>();
+    /// Creates a mesh, 32 bits indices are stored as 16 bits ones when they fit
+    ///
+    /// Each submesh is drawn with the material of its slot, without submeshes the whole mesh is
+    /// drawn with the first one
+    pub fn create_mesh<'a, T: Pod>(
+        &self, materials: &[MaterialRef], submeshes: &[Submesh], usage: MeshUsage,
+        topology: wgpu::PrimitiveTopology, indices: impl Into<MeshIndices<'a>>,
+        vertex_buffers: &[&[T]],
+    ) -> anyhow::Result<MeshRef> {
+        if materials.is_empty() {
+            bail!("A mesh needs at least one material slot");
+        }
+        let indices = indices.into();
+        let indices_size = indices.len();
+        let submeshes = Submesh::validate(submeshes, materials.len(), indices_size)?;
+        let (index_format, index_data) = indices.compact();
+        let (vertex_buffers, vertex_buffer_capacities) = vertex_buffers
+            .iter()
+            .map(|vertices| {
+                self.create_mesh_buffer(
+                    bytemuck::cast_slice(vertices),
+                    wgpu::BufferUsage::VERTEX,
+                    usage,
+                )
+            })
+            .unzip();
+        let (indices_buffer, indices_capacity) =
+            self.create_mesh_buffer(&index_data, wgpu::BufferUsage::INDEX, usage);
+
+        let mesh = Mesh {
+            materials: materials.into(),
+            submeshes,
+            usage,
+            vertex_buffers,
+            indices: indices_buffer,
+            indices_size,
+            index_format,
+            topology,
+            vertex_buffer_capacities,
+            indices_capacity,
+        };
+        for &material in materials {
+            self.prepare_material_pipeline(material, mesh.pipeline_key())?;
+        }
+
+        let mut meshes = self.meshes.write().unwrap();
+        let i = meshes.len();
+        meshes.push(mesh);
+
+        Ok(MeshRef(i))
+    }
+
+    /// Replaces the geometry of a mesh, the number of vertex buffers can't change
+    ///
+    /// Meshes created with [`MeshUsage::Dynamic`] are updated in place as long as the new data
+    /// fits in their buffers
+    pub fn update_mesh<'a, T: Pod>(
+        &self, mesh_ref: MeshRef, submeshes: &[Submesh], indices: impl Into<MeshIndices<'a>>,
+        vertex_buffers: &[&[T]],
+    ) -> anyhow::Result<()> {
+        let indices = indices.into();
+        let mut meshes = self.meshes.write().unwrap();
+        let mesh = &mut meshes[mesh_ref.0];
+        let submeshes = Submesh::validate(submeshes, mesh.materials.len(), indices.len())?;
+        if vertex_buffers.len() != mesh.vertex_buffers.len() {
+            bail!(
+                "The mesh has {} vertex buffers but {} were given",
+                mesh.vertex_buffers.len(),
+                vertex_buffers.len()
+            );
+        }
+
+        for ((buffer, capacity), vertices) in mesh
+            .vertex_buffers
+            .iter_mut()
+            .zip(mesh.vertex_buffer_capacities.iter_mut())
+            .zip(vertex_buffers)
+        {
+            self.update_mesh_buffer(
+                buffer,
+                capacity,
+                bytemuck::cast_slice(vertices),
+                wgpu::BufferUsage::VERTEX,
+                mesh.usage,
+            );
+        }
+        let (index_format, index_data) = indices.compact();
+        self.update_mesh_buffer(
+            &mut mesh.indices,
+            &mut mesh.indices_capacity,
+            &index_data,
+            wgpu::BufferUsage::INDEX,
+            mesh.usage,
+        );
+        mesh.indices_size = indices.len();
+        mesh.index_format = index_format;
+        mesh.submeshes = submeshes;
+        // Strips need a pipeline matching the index format
+        for &material in &mesh.materials {
+            self.prepare_material_pipeline(material, mesh.pipeline_key())?;
+        }
+
+        Ok(())
+    }
+    pub fn update_mesh_from_vertices<'a, V: Vertex>(
+        &self, mesh_ref: MeshRef, submeshes: &[Submesh], indices: impl Into<MeshIndices<'a>>,
+        vertices: &[V],
+    ) -> anyhow::Result<()> {
+        let materials = self.meshes.read().unwrap()[mesh_ref.0].materials.clone();
+        for material in materials {
+            self.check_vertex_layout::<V>(material)?;
+        }
+        self.update_mesh(mesh_ref, submeshes, indices, &[vertices])
+    }
+
+    /// Fails if the vertex layout of `V` isn't the one the material's shader was compiled with
+    fn check_vertex_layout<V: Vertex>(&self, material: MaterialRef) -> anyhow::Result<()> {
+        let shaders = self.shaders.read().unwrap();
+        let materials = self.materials.read().unwrap();
+        let shader = &shaders[materials[material.0].shader.0];
+        let layout = V::vertex_layout();
+        if shader.vertex_layouts.as_slice() != std::slice::from_ref(&layout) {
+            bail!(
+                "The vertex layout of {} doesn't match the shader of the material, expected {:?} \
+                 but got {:?}",
+                std::any::type_name::<V>(),
+                shader.vertex_layouts,
+                layout
+            );
+        }
+        Ok(())
+    }
+    /// Creates a mesh with a single interleaved vertex buffer
+    pub fn create_mesh_from_vertices<'a, V: Vertex>(
+        &self, materials: &[MaterialRef], submeshes: &[Submesh], usage: MeshUsage,
+        topology: wgpu::PrimitiveTopology, indices: impl Into<MeshIndices<'a>>, vertices: &[V],
+    ) -> anyhow::Result<MeshRef> {
+        for &material in materials {
+            self.check_vertex_layout::<V>(material)?;
+        }
+        self.create_mesh(materials, submeshes, usage, topology, indices, &[vertices])
+    }
+
+    /// Renders the world from its first enabled camera, meshes are drawn with the transforms
+    /// computed by the last [`update_global_transforms`](crate::transform::update_global_transforms)
+    ///
+    /// Moving entities are interpolated between their previous and current transforms by
+    /// `alpha`, see [`FixedTimestep::alpha`](crate::time::FixedTimestep::alpha).
+    pub fn render(&self, imgui_draw_data: &imgui::DrawData, world: &hecs::World, alpha: f32) {
+        let mut query = world.query::<(
+            &CameraComponent,
+            &TransformComponent,
+            Option<&PreviousTransformComponent>,
+            Option<&GlobalTransformComponent>,
+        )>();
         let current_camera = query
             .iter()
             .map(|(_, b)| b)
-            .filter(|(c, _)| c.is_enabled)
+            .filter(|(c, ..)| c.is_enabled)
             .next();
 
-        if let Some((current_camera, transform)) = current_camera {
-            self.render_camera(current_camera, &transform, imgui_draw_data, world);
+        if let Some((current_camera, transform, previous, global)) = current_camera {
+            // Interpolated like the meshes so a parented camera follows its parent
+            let transform = match global {
+                Some(global) => TransformComponent::from_homogeneous(&global.interpolated(alpha)),
+                None => previous.map_or(*transform, |previous| {
+                    previous.0.interpolate(transform, alpha)
+                }),
+            };
+            self.render_camera(current_camera, &transform, imgui_draw_data, world, alpha);
         }
     }
     pub fn render_camera(
         &self, camera: &CameraComponent, camera_transform: &TransformComponent,
-        imgui_draw_data: &imgui::DrawData, world: &hecs::World,
+        imgui_draw_data: &imgui::DrawData, world: &hecs::World, alpha: f32,
     ) {
+        self.prepare_override_pipelines(world);
+
         let meshes = self.meshes.read().unwrap();
         let materials = self.materials.read().unwrap();
         let camera_matrix = &*camera.matrix;
+        let view_projection = camera_matrix.clip_depth().conversion_to(self.clip_depth())
+            * camera_matrix.get_vp_matrix(camera_transform);
         let mut imgui_renderer = self.imgui_renderer.lock().unwrap();
+        let mut profiler = self.profiler.lock().unwrap();
+        profiler.begin_frame(&self.device);
 
-        self.queue.write_buffer(
-            &self.render_uniform_buffer,
-            offset_of!(RenderUniformBuffer, view_projection) as u64,
-            bytemuck::cast_slice(camera_matrix.get_vp_matrix(camera_transform).as_slice()),
-        );
+        let mut mesh_query = world.query::<(&MeshComponent, &GlobalTransformComponent)>();
+        let drawn_meshes = mesh_query
+            .iter()
+            .map(|(_, (mesh_component, transform))| (mesh_component, transform.interpolated(alpha)))
+            .collect::<Vec<_>>();
+        // Every mesh gets its own uniforms, a single buffer written between the draws would
+        // only hold the last model matrix when the commands are submitted
+        let mut render_uniforms = self.render_uniforms.lock().unwrap();
+        if render_uniforms.capacity < drawn_meshes.len() {
+            *render_uniforms = RenderUniforms::new(
+                &self.device,
+                &self.render_uniform_bind_group_layout,
+                drawn_meshes.len().next_power_of_two(),
+            );
+        }
+        let mut uniform_data = vec![0u8; drawn_meshes.len() * RenderUniforms::STRIDE as usize];
+        for ((_, model_matrix), data) in drawn_meshes
+            .iter()
+            .zip(uniform_data.chunks_mut(RenderUniforms::STRIDE as usize))
+        {
+            let uniforms = RenderUniformBuffer {
+                view_projection: view_projection.as_slice().try_into().unwrap(),
+                model_matrix: model_matrix.as_slice().try_into().unwrap(),
+            };
+            data[..std::mem::size_of::<RenderUniformBuffer>()]
+                .copy_from_slice(bytemuck::bytes_of(&uniforms));
+        }
+        if !uniform_data.is_empty() {
+            self.queue
+                .write_buffer(&render_uniforms.buffer, 0, &uniform_data);
+        }
+
+        // Submeshes without a material or a pipeline for their slot aren't drawn
+        let mut draws = Vec::new();
+        for (i, (mesh_component, _)) in drawn_meshes.iter().enumerate() {
+            let mesh = &meshes[mesh_component.mesh.0];
+            let pipeline_key = mesh.pipeline_key();
+            for submesh in &mesh.submeshes {
+                let material = match mesh_component.material(mesh, submesh.material_slot) {
+                    Some(material) => material,
+                    None => continue,
+                };
+                let material_data = &materials[material.0];
+                if material_data.render_pipelines.contains_key(&pipeline_key) {
+                    draws.push(DrawRecord {
+                        mesh: i,
+                        material,
+                        pipeline_key,
+                        material_bind_groups: material_data.bind_groups.len() as u32,
+                        indices: submesh.indices.clone(),
+                    });
+                }
+            }
+        }
+        let stats = RenderStats::from_draws(&draws);
 
         let frame = self
             .swap_chain
@@ -370,69 +1282,106 @@ impl Renderer {
         let mut encoder = self
             .device
             .create_command_encoder(&wgpu::CommandEncoderDescriptor { label: None });
-        {
-            let mut r_pass = encoder.begin_render_pass(&wgpu::RenderPassDescriptor {
-                label: None,
-                color_attachments: &[wgpu::RenderPassColorAttachment {
-                    view: &frame.view,
-                    resolve_target: None,
-                    ops: wgpu::Operations {
-                        load: match camera.clear_color {
-                            None => wgpu::LoadOp::Load,
-                            Some(c) => wgpu::LoadOp::Clear(c),
-                        },
-                        store: true,
-                    },
-                }],
-                depth_stencil_attachment: Some(wgpu::RenderPassDepthStencilAttachment {
-                    view: &self.depth_buffer_texture.view,
-                    depth_ops: Some(wgpu::Operations {
-                        load: wgpu::LoadOp::Clear(1.),
-                        store: true,
-                    }),
-                    stencil_ops: None,
-                }),
-            });
-            r_pass.set_bind_group(0, &self.render_uniform_bind_group, &[]);
+        profiler.begin_scope("Frame", &mut encoder);
 
-            let mut last_material = None;
-            world
-                .query::<&MeshComponent>()
-                .with::<TransformComponent>()
-                .into_iter()
-                .for_each(|(e, MeshComponent(mesh_ref))| {
-                    let mesh = &meshes[mesh_ref.0];
-                    if last_material != Some(mesh.material) {
-                        last_material = Some(mesh.material);
-                        let material = &materials[mesh.material.0];
-                        r_pass.set_pipeline(&material.render_pipeline);
-                        material
-                            .bind_groups
-                            .iter()
-                            .zip(1..)
-                            .for_each(|(bg, i)| r_pass.set_bind_group(i, bg, &[]));
-                    }
-                    let transform = get_global_transform(&world, e).unwrap();
-
-                    self.queue.write_buffer(
-                        &self.render_uniform_buffer,
-                        offset_of!(RenderUniformBuffer, model_matrix) as u64,
-                        bytemuck::cast_slice(transform.to_homogeneous().as_slice()),
-                    );
+        let mut transient_textures = self.transient_textures.lock().unwrap();
+        let mut graph = RenderGraph::new();
+        graph.set_far_depth(self.clip_depth().far());
+        let swap_chain_texture = graph.import_texture("Swap chain", &frame.view);
+        // Only used by the frame, its memory can be shared with other transient textures
+        let depth_texture = graph.create_texture("Depth buffer", TransientTextureDescriptor {
+            width: self.swap_chain_descriptor.width,
+            height: self.swap_chain_descriptor.height,
+            format: self.config.depth_format,
+        });
 
-                    r_pass.set_index_buffer(mesh.indices.slice(..), wgpu::IndexFormat::Uint32);
+        let mut main_pass = graph
+            .add_pass("Main scene")
+            .color_attachment(swap_chain_texture, camera.clear_color)
+            .depth_attachment(depth_texture, Some(self.clip_depth().far()));
+        if self.has_stencil() {
+            main_pass = main_pass.stencil(Some(0));
+        }
+        main_pass.execute(|ctx| {
+            let mut r_pass = ctx.begin_render_pass();
+            for (draw, mesh_changed, pipeline_changed) in with_state_changes(&draws) {
+                let mesh = &meshes[drawn_meshes[draw.mesh].0.mesh.0];
+                if mesh_changed {
+                    r_pass.set_bind_group(0, &render_uniforms.bind_group, &[
+                        RenderUniforms::dynamic_offset(draw.mesh),
+                    ]);
+                    r_pass.set_index_buffer(mesh.indices.slice(..), mesh.index_format);
                     for (vertex, i) in mesh.vertex_buffers.iter().zip(0..) {
                         r_pass.set_vertex_buffer(i, vertex.slice(..));
                     }
+                }
+                if pipeline_changed {
+                    let material = &materials[draw.material.0];
+                    r_pass.set_pipeline(&material.render_pipelines[&draw.pipeline_key]);
+                    r_pass.set_stencil_reference(material.stencil_reference);
+                    material
+                        .bind_groups
+                        .iter()
+                        .zip(1..)
+                        .for_each(|(bg, i)| r_pass.set_bind_group(i, bg, &[]));
+                }
+                r_pass.draw_indexed(draw.indices.clone(), 0, 0..1);
+            }
+        });
+        graph
+            .add_pass("ImGui")
+            .color_attachment(swap_chain_texture, None)
+            .depth_attachment(depth_texture, None)
+            .execute(|ctx| {
+                let mut r_pass = ctx.begin_render_pass();
+                imgui_renderer
+                    .render(imgui_draw_data, &self.queue, &self.device, &mut r_pass)
+                    .unwrap();
+            });
 
-                    r_pass.draw_indexed(0..mesh.indices_size as u32, 0, 0..1);
-                });
+        graph
+            .execute(
+                &self.device,
+                &mut encoder,
+                &mut transient_textures,
+                &mut profiler,
+            )
+            .expect("Failed to execute the render graph");
+        profiler.end_scope(&mut encoder);
+        profiler.resolve(&mut encoder);
+        self.queue.submit(Some(encoder.finish()));
+        profiler.end_frame();
+        *self.frame_stats.lock().unwrap() = stats;
+    }
 
-            imgui_renderer
-                .render(imgui_draw_data, &self.queue, &self.device, &mut r_pass)
-                .unwrap();
-        }
+    /// Counters of the last rendered frame along with the current memory usage
+    pub fn render_stats(&self) -> RenderStats {
+        let stats = *self.frame_stats.lock().unwrap();
 
-        self.queue.submit(Some(encoder.finish()));
+        let meshes = self.meshes.read().unwrap();
+        let materials = self.materials.read().unwrap();
+        let textures = self.textures.read().unwrap();
+        let buffer_sizes =
+            std::iter::once(
+                self.render_uniforms.lock().unwrap().capacity as u64 * RenderUniforms::STRIDE,
+            )
+            .chain(meshes.iter().map(|mesh| {
+                mesh.vertex_buffer_capacities.iter().sum::<u64>() + mesh.indices_capacity
+            }))
+            .chain(
+                materials
+                    .iter()
+                    .flat_map(|material| material.parameters.iter().flatten())
+                    .map(|parameter| match &parameter.value {
+                        MaterialParameterValue::Uniform { data, .. } => data.len() as u64,
+                        _ => 0,
+                    }),
+            );
+        let texture_sizes = std::iter::once(self.transient_textures.lock().unwrap().memory_size())
+            .chain(textures.iter().map(|texture| texture.memory_size));
+        stats.with_memory(buffer_sizes, texture_sizes)
     }
+
+    /// CPU and GPU timings of the last frames
+    pub fn profiler(&self) -> MutexGuard<'_, FrameProfiler> { self.profiler.lock().unwrap() }
 }
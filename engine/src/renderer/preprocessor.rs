@@ -0,0 +1,178 @@
+//! Minimal preprocessor run on WGSL sources before they are compiled.
+//!
+//! Supported directives, each on its own line:
+//! - `#include "name"` pastes one of the engine chunks listed in [`ENGINE_CHUNKS`],
+//!   a chunk is only included once
+//! - `#define NAME` and `#undef NAME`
+//! - `#ifdef NAME`, `#ifndef NAME`, `#else` and `#endif`
+
+use std::collections::HashSet;
+
+use anyhow::{anyhow, bail};
+
+/// Chunks available to `#include`
+pub const ENGINE_CHUNKS: &[(&str, &str)] = &[
+    ("view_uniforms", include_str!("shaders/view_uniforms.wgsl")),
+    ("transforms", include_str!("shaders/transforms.wgsl")),
+    ("lighting", include_str!("shaders/lighting.wgsl")),
+];
+
+struct Condition {
+    /// Whether the enclosing block is emitted
+    parent_active: bool,
+    value: bool,
+    in_else: bool,
+}
+impl Condition {
+    fn is_active(&self) -> bool { self.parent_active && self.value != self.in_else }
+}
+
+struct Preprocessor<'a> {
+    defines: HashSet<&'a str>,
+    included: HashSet<&'a str>,
+    output: String,
+}
+
+pub fn preprocess_wgsl(source: &str, defines: &[String]) -> anyhow::Result<String> {
+    let mut preprocessor = Preprocessor {
+        defines: defines.iter().map(String::as_str).collect(),
+        included: HashSet::new(),
+        output: String::with_capacity(source.len()),
+    };
+    preprocessor.process(source, "source")?;
+    Ok(preprocessor.output)
+}
+
+impl<'a> Preprocessor<'a> {
+    fn process(&mut self, source: &'a str, file_name: &str) -> anyhow::Result<()> {
+        let mut conditions: Vec<Condition> = Vec::new();
+
+        for (line_index, line) in source.lines().enumerate() {
+            let error = |message: String| anyhow!("{}:{}: {}", file_name, line_index + 1, message);
+            let active = !matches!(conditions.last(), Some(c) if !c.is_active());
+
+            let directive = match line.trim_start().strip_prefix('#') {
+                Some(directive) => directive.trim(),
+                None => {
+                    if active {
+                        self.output.push_str(line);
+                    }
+                    self.output.push('\n');
+                    continue;
+                }
+            };
+            let (name, argument) = match directive.split_once(char::is_whitespace) {
+                Some((name, argument)) => (name, argument.trim()),
+                None => (directive, ""),
+            };
+            let require_argument = || {
+                if argument.is_empty() {
+                    Err(error(format!("#{} requires an argument", name)))
+                }
+                else {
+                    Ok(argument)
+                }
+            };
+
+            match name {
+                "ifdef" | "ifndef" => {
+                    let defined = self.defines.contains(require_argument()?);
+                    conditions.push(Condition {
+                        parent_active: active,
+                        value: defined == (name == "ifdef"),
+                        in_else: false,
+                    });
+                }
+                "else" => match conditions.last_mut() {
+                    Some(condition) if !condition.in_else => condition.in_else = true,
+                    _ => return Err(error("Unexpected #else".into())),
+                },
+                "endif" => {
+                    conditions
+                        .pop()
+                        .ok_or_else(|| error("Unexpected #endif".into()))?;
+                }
+                _ if !active => {}
+                "define" => {
+                    self.defines.insert(require_argument()?);
+                }
+                "undef" => {
+                    self.defines.remove(require_argument()?);
+                }
+                "include" => {
+                    let chunk_name =
+                        require_argument()?.trim_matches(|c| c == '"' || c == '<' || c == '>');
+                    let chunk = ENGINE_CHUNKS
+                        .iter()
+                        .find(|(name, _)| *name == chunk_name)
+                        .map(|(_, chunk)| *chunk)
+                        .ok_or_else(|| error(format!("Unknown include '{}'", chunk_name)))?;
+                    if self.included.insert(chunk_name) {
+                        self.process(chunk, chunk_name)?;
+                    }
+                    continue;
+                }
+                _ => return Err(error(format!("Unknown directive #{}", name))),
+            }
+            // Directives are replaced by empty lines
+            self.output.push('\n');
+        }
+
+        if !conditions.is_empty() {
+            bail!("{}: Missing #endif", file_name);
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn lines(source: &str) -> Vec<&str> {
+        source.lines().filter(|l| !l.trim().is_empty()).collect()
+    }
+
+    #[test]
+    fn conditionals() {
+        let source = "\
+#define A
+#ifdef A
+a
+#ifndef B
+not b
+#else
+b
+#endif
+#else
+not a
+#endif
+#ifdef C
+c
+#endif";
+        assert_eq!(lines(&preprocess_wgsl(source, &[]).unwrap()), [
+            "a", "not b"
+        ]);
+        assert_eq!(
+            lines(&preprocess_wgsl(source, &["B".into(), "C".into()]).unwrap()),
+            ["a", "b", "c"]
+        );
+    }
+
+    #[test]
+    fn includes_are_pasted_once() {
+        let output =
+            preprocess_wgsl("#include \"transforms\"\n#include \"view_uniforms\"", &[]).unwrap();
+        assert_eq!(output.matches("struct RenderUniforms").count(), 1);
+        assert!(output.contains("fn model_to_clip"));
+    }
+
+    #[test]
+    fn errors() {
+        assert!(preprocess_wgsl("#ifdef A", &[]).is_err());
+        assert!(preprocess_wgsl("#endif", &[]).is_err());
+        assert!(preprocess_wgsl("#ifdef A\n#else\n#else\n#endif", &[]).is_err());
+        assert!(preprocess_wgsl("#include \"unknown\"", &[]).is_err());
+        assert!(preprocess_wgsl("#pragma once", &[]).is_err());
+    }
+}
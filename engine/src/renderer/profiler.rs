@@ -0,0 +1,410 @@
+use std::{
+    collections::VecDeque,
+    future::Future,
+    io::{self, Write},
+    pin::Pin,
+    task::{Context, Poll, RawWaker, RawWakerVTable, Waker},
+    time::Instant,
+};
+
+use serde::Serialize;
+
+/// Rolling timings of a scope over the last [`FrameProfiler::HISTORY_LENGTH`] frames, in
+/// milliseconds
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct TimingStats {
+    pub last: f32,
+    pub average: f32,
+    pub min: f32,
+    pub max: f32,
+}
+impl TimingStats {
+    fn from_samples(samples: &VecDeque<f32>) -> Option<Self> {
+        let last = *samples.back()?;
+        Some(Self {
+            last,
+            average: samples.iter().sum::<f32>() / samples.len() as f32,
+            min: samples.iter().copied().fold(f32::INFINITY, f32::min),
+            max: samples.iter().copied().fold(f32::NEG_INFINITY, f32::max),
+        })
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct ScopeStats {
+    pub name: &'static str,
+    /// Time spent recording the scope's commands
+    pub cpu: TimingStats,
+    /// Time spent executing the scope's commands, only available with
+    /// [`wgpu::Features::TIMESTAMP_QUERY`]
+    pub gpu: Option<TimingStats>,
+}
+
+struct ScopeHistory {
+    name: &'static str,
+    cpu: VecDeque<f32>,
+    gpu: VecDeque<f32>,
+}
+
+struct FrameScope {
+    name: &'static str,
+    cpu_start: Instant,
+    cpu_end: Instant,
+    /// Index of the timestamp written at the start of the scope, the end one follows it
+    query: Option<u32>,
+}
+
+#[derive(Debug, Clone, Copy)]
+enum TraceTimeline {
+    Cpu = 0,
+    Gpu = 1,
+}
+struct TraceEvent {
+    name: &'static str,
+    timeline: TraceTimeline,
+    /// Microseconds since the creation of the profiler
+    start: f64,
+    duration: f64,
+}
+
+type MapFuture = Pin<Box<dyn Future<Output = Result<(), wgpu::BufferAsyncError>> + Send>>;
+
+struct GpuTimer {
+    query_set: wgpu::QuerySet,
+    read_buffer: wgpu::Buffer,
+    /// Nanoseconds per timestamp tick
+    period: f32,
+    /// Mapping of the read buffer and the scopes of the frame it contains
+    pending: Option<(MapFuture, Vec<FrameScope>)>,
+}
+
+/// Records CPU and GPU timings of the named scopes of each frame
+///
+/// GPU timings are read back asynchronously, frames rendered while a read back is in flight only
+/// get CPU timings
+pub struct FrameProfiler {
+    gpu: Option<GpuTimer>,
+    creation: Instant,
+
+    scopes: Vec<FrameScope>,
+    open_scopes: Vec<usize>,
+    next_query: u32,
+    gpu_frame: bool,
+
+    history: Vec<ScopeHistory>,
+    trace: VecDeque<TraceEvent>,
+}
+
+impl FrameProfiler {
+    pub const HISTORY_LENGTH: usize = 120;
+    const TRACE_LENGTH: usize = 16384;
+    const MAX_SCOPES: u32 = 32;
+
+    pub(crate) fn new(device: &wgpu::Device, queue: &wgpu::Queue) -> Self {
+        let gpu = device
+            .features()
+            .contains(wgpu::Features::TIMESTAMP_QUERY)
+            .then(|| GpuTimer {
+                query_set: device.create_query_set(&wgpu::QuerySetDescriptor {
+                    ty: wgpu::QueryType::Timestamp,
+                    count: Self::MAX_SCOPES * 2,
+                }),
+                read_buffer: device.create_buffer(&wgpu::BufferDescriptor {
+                    label: Some("Profiler timestamps"),
+                    size: (Self::MAX_SCOPES * 2 * wgpu::QUERY_SIZE) as wgpu::BufferAddress,
+                    usage: wgpu::BufferUsage::MAP_READ | wgpu::BufferUsage::COPY_DST,
+                    mapped_at_creation: false,
+                }),
+                period: queue.get_timestamp_period(),
+                pending: None,
+            });
+
+        Self {
+            gpu,
+            creation: Instant::now(),
+            scopes: Vec::new(),
+            open_scopes: Vec::new(),
+            next_query: 0,
+            gpu_frame: false,
+            history: Vec::new(),
+            trace: VecDeque::new(),
+        }
+    }
+
+    pub fn has_gpu_timings(&self) -> bool { self.gpu.is_some() }
+
+    pub(crate) fn begin_frame(&mut self, device: &wgpu::Device) {
+        device.poll(wgpu::Maintain::Poll);
+        self.read_back();
+
+        self.scopes.clear();
+        self.open_scopes.clear();
+        self.next_query = 0;
+        self.gpu_frame = matches!(&self.gpu, Some(gpu) if gpu.pending.is_none());
+    }
+    pub(crate) fn begin_scope(&mut self, name: &'static str, encoder: &mut wgpu::CommandEncoder) {
+        let mut query = None;
+        if let (true, Some(gpu)) = (self.gpu_frame, &self.gpu) {
+            if self.next_query < Self::MAX_SCOPES * 2 {
+                encoder.write_timestamp(&gpu.query_set, self.next_query);
+                query = Some(self.next_query);
+                self.next_query += 2;
+            }
+        }
+        self.open_scopes.push(self.scopes.len());
+        let now = Instant::now();
+        self.scopes.push(FrameScope {
+            name,
+            cpu_start: now,
+            cpu_end: now,
+            query,
+        });
+    }
+    pub(crate) fn end_scope(&mut self, encoder: &mut wgpu::CommandEncoder) {
+        let scope = &mut self.scopes[self.open_scopes.pop().expect("No scope to end")];
+        scope.cpu_end = Instant::now();
+        if let (Some(query), Some(gpu)) = (scope.query, &self.gpu) {
+            encoder.write_timestamp(&gpu.query_set, query + 1);
+        }
+    }
+    /// Resolves the frame's timestamps, must be called before the encoder is submitted
+    pub(crate) fn resolve(&mut self, encoder: &mut wgpu::CommandEncoder) {
+        debug_assert!(self.open_scopes.is_empty(), "Unended profiler scopes");
+        if let (true, Some(gpu)) = (self.next_query > 0, &self.gpu) {
+            encoder.resolve_query_set(&gpu.query_set, 0..self.next_query, &gpu.read_buffer, 0);
+        }
+    }
+    /// Starts reading back the resolved timestamps, must be called after the encoder is submitted
+    pub(crate) fn end_frame(&mut self) {
+        let scopes = std::mem::take(&mut self.scopes);
+        for scope in &scopes {
+            let cpu = (scope.cpu_end - scope.cpu_start).as_secs_f32() * 1000.;
+            Self::push_sample(&mut self.history_of(scope.name).cpu, cpu);
+            self.push_trace(TraceEvent {
+                name: scope.name,
+                timeline: TraceTimeline::Cpu,
+                start: (scope.cpu_start - self.creation).as_secs_f64() * 1e6,
+                duration: cpu as f64 * 1000.,
+            });
+        }
+
+        if let (true, Some(gpu)) = (self.next_query > 0, &mut self.gpu) {
+            let future = gpu.read_buffer.slice(..).map_async(wgpu::MapMode::Read);
+            gpu.pending = Some((Box::pin(future), scopes));
+        }
+    }
+
+    fn read_back(&mut self) {
+        let gpu = match &mut self.gpu {
+            Some(gpu) => gpu,
+            None => return,
+        };
+        let ready = match &mut gpu.pending {
+            Some((future, _)) => poll_now(future.as_mut()),
+            None => return,
+        };
+        let result = match ready {
+            Some(result) => result,
+            None => return,
+        };
+        let (_, scopes) = gpu.pending.take().unwrap();
+        if result.is_err() {
+            return;
+        }
+        let period = gpu.period as f64;
+
+        let timestamps: Vec<u64> = {
+            let data = gpu.read_buffer.slice(..).get_mapped_range();
+            data.chunks_exact(8)
+                .map(|bytes| {
+                    u64::from_le_bytes([
+                        bytes[0], bytes[1], bytes[2], bytes[3], bytes[4], bytes[5], bytes[6],
+                        bytes[7],
+                    ])
+                })
+                .collect()
+        };
+        gpu.read_buffer.unmap();
+
+        // GPU timestamps have no defined origin, they are aligned on the start of the frame's
+        // first scope on the CPU for traces
+        let origin = match scopes.iter().find(|scope| scope.query.is_some()) {
+            Some(first) => (
+                timestamps[first.query.unwrap() as usize],
+                (first.cpu_start - self.creation).as_secs_f64() * 1e6,
+            ),
+            None => return,
+        };
+        for scope in &scopes {
+            let query = match scope.query {
+                Some(query) => query as usize,
+                None => continue,
+            };
+            let (start, end) = (timestamps[query], timestamps[query + 1]);
+            let duration = end.saturating_sub(start) as f64 * period / 1000.;
+            Self::push_sample(
+                &mut self.history_of(scope.name).gpu,
+                duration as f32 / 1000.,
+            );
+            self.push_trace(TraceEvent {
+                name: scope.name,
+                timeline: TraceTimeline::Gpu,
+                start: origin.1 + start.wrapping_sub(origin.0) as i64 as f64 * period / 1000.,
+                duration,
+            });
+        }
+    }
+
+    fn history_of(&mut self, name: &'static str) -> &mut ScopeHistory {
+        let i = match self.history.iter().position(|history| history.name == name) {
+            Some(i) => i,
+            None => {
+                self.history.push(ScopeHistory {
+                    name,
+                    cpu: VecDeque::new(),
+                    gpu: VecDeque::new(),
+                });
+                self.history.len() - 1
+            }
+        };
+        &mut self.history[i]
+    }
+    fn push_sample(samples: &mut VecDeque<f32>, sample: f32) {
+        if samples.len() == Self::HISTORY_LENGTH {
+            samples.pop_front();
+        }
+        samples.push_back(sample);
+    }
+    fn push_trace(&mut self, event: TraceEvent) {
+        if self.trace.len() == Self::TRACE_LENGTH {
+            self.trace.pop_front();
+        }
+        self.trace.push_back(event);
+    }
+
+    /// Statistics of every scope recorded so far, in the order they were first recorded
+    pub fn stats(&self) -> Vec<ScopeStats> {
+        self.history
+            .iter()
+            .filter_map(|history| {
+                Some(ScopeStats {
+                    name: history.name,
+                    cpu: TimingStats::from_samples(&history.cpu)?,
+                    gpu: TimingStats::from_samples(&history.gpu),
+                })
+            })
+            .collect()
+    }
+
+    /// Writes the recent scopes in the Chrome trace event format, to be opened with
+    /// `chrome://tracing` or Perfetto
+    pub fn write_chrome_trace(&self, mut writer: impl Write) -> io::Result<()> {
+        let events = self
+            .trace
+            .iter()
+            .map(|event| ChromeTraceEvent {
+                name: event.name,
+                cat: match event.timeline {
+                    TraceTimeline::Cpu => "cpu",
+                    TraceTimeline::Gpu => "gpu",
+                },
+                ph: "X",
+                pid: 0,
+                tid: event.timeline as u32,
+                ts: event.start,
+                dur: event.duration,
+            })
+            .collect::<Vec<_>>();
+        serde_json::to_writer(&mut writer, &events)?;
+        writeln!(writer)
+    }
+}
+
+/// Complete event of the Chrome trace event format, spanning from `ts` to `ts + dur`
+#[derive(Serialize)]
+struct ChromeTraceEvent {
+    name: &'static str,
+    cat: &'static str,
+    ph: &'static str,
+    pid: u32,
+    /// The CPU and GPU timelines are shown as separate threads
+    tid: u32,
+    ts: f64,
+    dur: f64,
+}
+
+/// Polls a future once, the profiler relies on [`wgpu::Device::poll`] instead of wakers
+fn poll_now<F: Future + ?Sized>(future: Pin<&mut F>) -> Option<F::Output> {
+    fn noop_raw_waker() -> RawWaker {
+        fn clone(_: *const ()) -> RawWaker { noop_raw_waker() }
+        fn noop(_: *const ()) {}
+        static VTABLE: RawWakerVTable = RawWakerVTable::new(clone, noop, noop, noop);
+        RawWaker::new(std::ptr::null(), &VTABLE)
+    }
+    let waker = unsafe { Waker::from_raw(noop_raw_waker()) };
+    match future.poll(&mut Context::from_waker(&waker)) {
+        Poll::Ready(output) => Some(output),
+        Poll::Pending => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn timing_stats() {
+        assert_eq!(TimingStats::from_samples(&VecDeque::new()), None);
+        let samples = [2., 1., 6.].iter().copied().collect();
+        assert_eq!(
+            TimingStats::from_samples(&samples),
+            Some(TimingStats {
+                last: 6.,
+                average: 3.,
+                min: 1.,
+                max: 6.,
+            })
+        );
+    }
+
+    #[test]
+    fn chrome_trace() {
+        let profiler = FrameProfiler {
+            gpu: None,
+            creation: Instant::now(),
+            scopes: Vec::new(),
+            open_scopes: Vec::new(),
+            next_query: 0,
+            gpu_frame: false,
+            history: Vec::new(),
+            trace: vec![
+                TraceEvent {
+                    name: "Passe d'ombre \"é\"",
+                    timeline: TraceTimeline::Cpu,
+                    start: 10.,
+                    duration: 2.5,
+                },
+                TraceEvent {
+                    name: "Frame",
+                    timeline: TraceTimeline::Gpu,
+                    start: 11.,
+                    duration: 4.,
+                },
+            ]
+            .into(),
+        };
+        let mut trace = Vec::new();
+        profiler.write_chrome_trace(&mut trace).unwrap();
+
+        let events: serde_json::Value = serde_json::from_slice(&trace).unwrap();
+        assert_eq!(
+            events,
+            serde_json::json!([
+                {"name": "Passe d'ombre \"é\"", "cat": "cpu", "ph": "X", "pid": 0, "tid": 0,
+                 "ts": 10.0, "dur": 2.5},
+                {"name": "Frame", "cat": "gpu", "ph": "X", "pid": 0, "tid": 1, "ts": 11.0,
+                 "dur": 4.0},
+            ])
+        );
+    }
+}
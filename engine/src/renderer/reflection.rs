@@ -0,0 +1,516 @@
+use std::num::NonZeroU64;
+
+use anyhow::{anyhow, bail};
+use smallvec::SmallVec;
+
+use super::VertexLayout;
+
+/// Bindings and vertex inputs of a WGSL module, as declared in its source
+#[derive(Debug, Clone, PartialEq)]
+pub struct ShaderReflection {
+    /// Indexed by group, empty groups are kept so that indices stay aligned
+    pub bind_groups: Vec<Vec<ReflectedBinding>>,
+    /// Inputs of the `vertex` entry point, sorted by location
+    pub vertex_inputs: Vec<ReflectedVertexInput>,
+}
+#[derive(Debug, Clone, PartialEq)]
+pub struct ReflectedBinding {
+    pub name: Option<String>,
+    pub binding: u32,
+    pub visibility: wgpu::ShaderStage,
+    pub ty: wgpu::BindingType,
+    /// Members of the struct for uniform buffer bindings
+    pub members: Vec<ReflectedMember>,
+}
+impl ReflectedBinding {
+    /// Whether materials created for `other` can use this binding, the shader stages using it
+    /// may differ
+    pub fn is_compatible_with(&self, other: &Self) -> bool {
+        self.name == other.name
+            && self.binding == other.binding
+            && self.ty == other.ty
+            && self.members == other.members
+    }
+}
+#[derive(Debug, Clone, PartialEq)]
+pub struct ReflectedMember {
+    pub name: Option<String>,
+    pub offset: u32,
+    pub size: u32,
+    pub ty: UniformType,
+}
+/// Types of uniform struct members that can be set as material parameters
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum UniformType {
+    Float,
+    Vec2,
+    Vec3,
+    Vec4,
+    Mat4,
+    Other,
+}
+impl UniformType {
+    fn from_inner(inner: &naga::TypeInner) -> Self {
+        use naga::{ScalarKind::Float, VectorSize::*};
+        match *inner {
+            naga::TypeInner::Scalar {
+                kind: Float,
+                width: 4,
+            } => Self::Float,
+            naga::TypeInner::Vector {
+                size,
+                kind: Float,
+                width: 4,
+            } => match size {
+                Bi => Self::Vec2,
+                Tri => Self::Vec3,
+                Quad => Self::Vec4,
+            },
+            naga::TypeInner::Matrix {
+                columns: Quad,
+                rows: Quad,
+                width: 4,
+            } => Self::Mat4,
+            _ => Self::Other,
+        }
+    }
+}
+#[derive(Debug, Clone, PartialEq)]
+pub struct ReflectedVertexInput {
+    pub name: Option<String>,
+    pub location: u32,
+    pub format: wgpu::VertexFormat,
+}
+
+impl ShaderReflection {
+    pub const VERTEX_ENTRY_POINT: &'static str = "vertex";
+    pub const FRAGMENT_ENTRY_POINT: &'static str = "fragment";
+
+    /// Parses and validates the WGSL source with naga, errors are returned
+    /// instead of being treated as fatal by wgpu
+    pub fn from_wgsl(source: &str) -> anyhow::Result<Self> {
+        let module = naga::front::wgsl::parse_str(source)
+            .map_err(|error| anyhow!("{}", error.emit_to_string()))?;
+        let info =
+            naga::valid::Validator::new(naga::valid::ValidationFlags::all()).validate(&module)?;
+        Self::from_module(&module, &info)
+    }
+
+    pub fn from_module(
+        module: &naga::Module, info: &naga::valid::ModuleInfo,
+    ) -> anyhow::Result<Self> {
+        let entry_point_index = |name: &str, stage: naga::ShaderStage| {
+            module
+                .entry_points
+                .iter()
+                .position(|e| e.name == name && e.stage == stage)
+                .ok_or_else(|| anyhow!("Missing {:?} entry point '{}'", stage, name))
+        };
+        let vertex_index = entry_point_index(Self::VERTEX_ENTRY_POINT, naga::ShaderStage::Vertex)?;
+        let fragment_index =
+            entry_point_index(Self::FRAGMENT_ENTRY_POINT, naga::ShaderStage::Fragment)?;
+
+        let mut bind_groups: Vec<Vec<ReflectedBinding>> = Vec::new();
+        for (handle, global) in module.global_variables.iter() {
+            let resource_binding = match &global.binding {
+                Some(b) => b,
+                None => continue,
+            };
+
+            let mut visibility = wgpu::ShaderStage::NONE;
+            if !info.get_entry_point(vertex_index)[handle].is_empty() {
+                visibility |= wgpu::ShaderStage::VERTEX;
+            }
+            if !info.get_entry_point(fragment_index)[handle].is_empty() {
+                visibility |= wgpu::ShaderStage::FRAGMENT;
+            }
+            if visibility.is_empty() {
+                // Declared but unused bindings are kept so that materials
+                // can set them regardless of the permutation
+                visibility = wgpu::ShaderStage::VERTEX | wgpu::ShaderStage::FRAGMENT;
+            }
+
+            let inner = &module.types[global.ty].inner;
+            let ty = binding_type(global, inner, &module.constants).ok_or_else(|| {
+                anyhow!(
+                    "Unsupported binding type for '{}' at group {} binding {}",
+                    global.name.as_deref().unwrap_or("?"),
+                    resource_binding.group,
+                    resource_binding.binding
+                )
+            })?;
+            let members = match (global.class, inner) {
+                (naga::StorageClass::Uniform, naga::TypeInner::Struct { members, .. }) => members
+                    .iter()
+                    .map(|member| ReflectedMember {
+                        name: member.name.clone(),
+                        offset: member.offset,
+                        size: module.types[member.ty].inner.span(&module.constants),
+                        ty: UniformType::from_inner(&module.types[member.ty].inner),
+                    })
+                    .collect(),
+                _ => Vec::new(),
+            };
+
+            let group = resource_binding.group as usize;
+            if bind_groups.len() <= group {
+                bind_groups.resize_with(group + 1, Vec::new);
+            }
+            bind_groups[group].push(ReflectedBinding {
+                name: global.name.clone(),
+                binding: resource_binding.binding,
+                visibility,
+                ty,
+                members,
+            });
+        }
+        bind_groups
+            .iter_mut()
+            .for_each(|g| g.sort_by_key(|binding| binding.binding));
+
+        let vertex_function = &module.entry_points[vertex_index].function;
+        let mut vertex_inputs = Vec::new();
+        for argument in &vertex_function.arguments {
+            let inner = &module.types[argument.ty].inner;
+            match (&argument.binding, inner) {
+                (Some(binding), _) => {
+                    vertex_inputs.extend(vertex_input(argument.name.as_ref(), binding, inner)?)
+                }
+                (None, naga::TypeInner::Struct { members, .. }) => {
+                    for member in members {
+                        if let Some(binding) = &member.binding {
+                            vertex_inputs.extend(vertex_input(
+                                member.name.as_ref(),
+                                binding,
+                                &module.types[member.ty].inner,
+                            )?);
+                        }
+                    }
+                }
+                (None, _) => {}
+            }
+        }
+        vertex_inputs.sort_by_key(|input| input.location);
+
+        Ok(Self {
+            bind_groups,
+            vertex_inputs,
+        })
+    }
+
+    /// Whether materials created for `other` can be used with this shader, see
+    /// [`ReflectedBinding::is_compatible_with`]
+    pub fn has_compatible_bind_groups(&self, other: &Self) -> bool {
+        self.bind_groups.len() == other.bind_groups.len()
+            && self
+                .bind_groups
+                .iter()
+                .zip(&other.bind_groups)
+                .all(|(a, b)| {
+                    a.len() == b.len() && a.iter().zip(b).all(|(a, b)| a.is_compatible_with(b))
+                })
+    }
+
+    pub fn bind_group_layout_entries(&self, group: usize) -> Vec<wgpu::BindGroupLayoutEntry> {
+        self.bind_groups
+            .get(group)
+            .into_iter()
+            .flatten()
+            .map(|binding| wgpu::BindGroupLayoutEntry {
+                binding: binding.binding,
+                visibility: binding.visibility,
+                ty: binding.ty,
+                count: None,
+            })
+            .collect()
+    }
+
+    /// One vertex buffer per input, each containing a tightly packed attribute
+    pub fn separate_vertex_layouts(&self) -> SmallVec<[VertexLayout; 4]> {
+        self.vertex_inputs
+            .iter()
+            .map(|input| VertexLayout::single_attribute(input.format, input.location))
+            .collect()
+    }
+
+    /// Checks that every vertex input is provided by exactly one of the layouts with the right format
+    pub fn validate_vertex_layouts(&self, layouts: &[VertexLayout]) -> anyhow::Result<()> {
+        for input in &self.vertex_inputs {
+            let mut attributes = layouts
+                .iter()
+                .flat_map(|layout| layout.attributes.iter())
+                .filter(|attribute| attribute.shader_location == input.location);
+            let attribute = attributes.next().ok_or_else(|| {
+                anyhow!(
+                    "Vertex input '{}' at location {} isn't provided by the vertex layouts",
+                    input.name.as_deref().unwrap_or("?"),
+                    input.location
+                )
+            })?;
+            if attributes.next().is_some() {
+                bail!(
+                    "Vertex input location {} is provided multiple times",
+                    input.location
+                );
+            }
+            if attribute.format != input.format {
+                bail!(
+                    "Vertex input '{}' at location {} is declared as {:?} but the layout provides \
+                     {:?}",
+                    input.name.as_deref().unwrap_or("?"),
+                    input.location,
+                    input.format,
+                    attribute.format
+                );
+            }
+        }
+        Ok(())
+    }
+}
+
+fn binding_type(
+    global: &naga::GlobalVariable, inner: &naga::TypeInner, constants: &naga::Arena<naga::Constant>,
+) -> Option<wgpu::BindingType> {
+    Some(match (global.class, inner) {
+        (naga::StorageClass::Uniform, _) => wgpu::BindingType::Buffer {
+            ty: wgpu::BufferBindingType::Uniform,
+            has_dynamic_offset: false,
+            min_binding_size: NonZeroU64::new(inner.span(constants) as u64),
+        },
+        (naga::StorageClass::Storage, _) => wgpu::BindingType::Buffer {
+            ty: wgpu::BufferBindingType::Storage {
+                read_only: !global.storage_access.contains(naga::StorageAccess::STORE),
+            },
+            has_dynamic_offset: false,
+            min_binding_size: NonZeroU64::new(inner.span(constants) as u64),
+        },
+        (naga::StorageClass::Handle, naga::TypeInner::Sampler { comparison }) => {
+            wgpu::BindingType::Sampler {
+                filtering: true,
+                comparison: *comparison,
+            }
+        }
+        (
+            naga::StorageClass::Handle,
+            naga::TypeInner::Image {
+                dim,
+                arrayed,
+                class,
+            },
+        ) => {
+            let view_dimension = match (dim, arrayed) {
+                (naga::ImageDimension::D1, false) => wgpu::TextureViewDimension::D1,
+                (naga::ImageDimension::D2, false) => wgpu::TextureViewDimension::D2,
+                (naga::ImageDimension::D2, true) => wgpu::TextureViewDimension::D2Array,
+                (naga::ImageDimension::D3, false) => wgpu::TextureViewDimension::D3,
+                (naga::ImageDimension::Cube, false) => wgpu::TextureViewDimension::Cube,
+                (naga::ImageDimension::Cube, true) => wgpu::TextureViewDimension::CubeArray,
+                _ => return None,
+            };
+            let (sample_type, multisampled) = match class {
+                naga::ImageClass::Sampled { kind, multi } => (
+                    match kind {
+                        naga::ScalarKind::Float => {
+                            wgpu::TextureSampleType::Float { filterable: true }
+                        }
+                        naga::ScalarKind::Sint => wgpu::TextureSampleType::Sint,
+                        naga::ScalarKind::Uint => wgpu::TextureSampleType::Uint,
+                        naga::ScalarKind::Bool => return None,
+                    },
+                    *multi,
+                ),
+                naga::ImageClass::Depth => (wgpu::TextureSampleType::Depth, false),
+                naga::ImageClass::Storage(_) => return None,
+            };
+            wgpu::BindingType::Texture {
+                sample_type,
+                view_dimension,
+                multisampled,
+            }
+        }
+        _ => return None,
+    })
+}
+
+fn vertex_input(
+    name: Option<&String>, binding: &naga::Binding, inner: &naga::TypeInner,
+) -> anyhow::Result<Option<ReflectedVertexInput>> {
+    let location = match binding {
+        naga::Binding::Location { location, .. } => *location,
+        naga::Binding::BuiltIn(_) => return Ok(None),
+    };
+    let (size, kind, width) = match *inner {
+        naga::TypeInner::Scalar { kind, width } => (1, kind, width),
+        naga::TypeInner::Vector { size, kind, width } => (size as u8, kind, width),
+        _ => bail!("Unsupported type for vertex input at location {}", location),
+    };
+    use naga::ScalarKind::*;
+    use wgpu::VertexFormat::*;
+    let format = match (kind, width, size) {
+        (Float, 4, 1) => Float32,
+        (Float, 4, 2) => Float32x2,
+        (Float, 4, 3) => Float32x3,
+        (Float, 4, 4) => Float32x4,
+        (Float, 8, 1) => Float64,
+        (Float, 8, 2) => Float64x2,
+        (Float, 8, 3) => Float64x3,
+        (Float, 8, 4) => Float64x4,
+        (Uint, 4, 1) => Uint32,
+        (Uint, 4, 2) => Uint32x2,
+        (Uint, 4, 3) => Uint32x3,
+        (Uint, 4, 4) => Uint32x4,
+        (Sint, 4, 1) => Sint32,
+        (Sint, 4, 2) => Sint32x2,
+        (Sint, 4, 3) => Sint32x3,
+        (Sint, 4, 4) => Sint32x4,
+        _ => bail!("Unsupported type for vertex input at location {}", location),
+    };
+    Ok(Some(ReflectedVertexInput {
+        name: name.cloned(),
+        location,
+        format,
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SOURCE: &str = r#"
+[[block]] struct RenderUniforms {
+    view_projection: mat4x4<f32>;
+    model_matrix: mat4x4<f32>;
+};
+[[group(0), binding(0)]]
+var<uniform> render_uniforms: RenderUniforms;
+
+[[block]] struct Uniforms {
+    color: vec4<f32>;
+    intensity: f32;
+};
+[[group(1), binding(0)]]
+var<uniform> uniforms: Uniforms;
+[[group(1), binding(1)]]
+var diffuse_texture: texture_2d<f32>;
+[[group(1), binding(2)]]
+var diffuse_sampler: sampler;
+
+[[stage(vertex)]]
+fn vertex(
+    [[location(0)]] position: vec3<f32>,
+    [[location(2)]] uv: vec2<f32>,
+) -> [[builtin(position)]] vec4<f32> {
+    return render_uniforms.view_projection * vec4<f32>(position + vec3<f32>(uv, 0.), 1.0);
+}
+
+[[stage(fragment)]]
+fn fragment() -> [[location(0)]] vec4<f32> {
+    return textureSample(diffuse_texture, diffuse_sampler, vec2<f32>(0., 0.))
+        * uniforms.color * uniforms.intensity;
+}
+"#;
+
+    #[test]
+    fn reflect_bindings_and_vertex_inputs() {
+        let reflection = ShaderReflection::from_wgsl(SOURCE).unwrap();
+
+        assert_eq!(reflection.bind_groups.len(), 2);
+        let render_uniforms = &reflection.bind_groups[0][0];
+        assert_eq!(render_uniforms.visibility, wgpu::ShaderStage::VERTEX);
+        assert_eq!(render_uniforms.ty, wgpu::BindingType::Buffer {
+            ty: wgpu::BufferBindingType::Uniform,
+            has_dynamic_offset: false,
+            min_binding_size: NonZeroU64::new(128),
+        });
+
+        let group = &reflection.bind_groups[1];
+        assert_eq!(
+            group.iter().map(|b| b.name.as_deref()).collect::<Vec<_>>(),
+            [
+                Some("uniforms"),
+                Some("diffuse_texture"),
+                Some("diffuse_sampler")
+            ]
+        );
+        assert!(group
+            .iter()
+            .all(|b| b.visibility == wgpu::ShaderStage::FRAGMENT));
+        assert_eq!(group[0].members, [
+            ReflectedMember {
+                name: Some("color".into()),
+                offset: 0,
+                size: 16,
+                ty: UniformType::Vec4,
+            },
+            ReflectedMember {
+                name: Some("intensity".into()),
+                offset: 16,
+                size: 4,
+                ty: UniformType::Float,
+            },
+        ]);
+        assert_eq!(group[1].ty, wgpu::BindingType::Texture {
+            sample_type: wgpu::TextureSampleType::Float { filterable: true },
+            view_dimension: wgpu::TextureViewDimension::D2,
+            multisampled: false,
+        });
+
+        assert_eq!(
+            reflection
+                .vertex_inputs
+                .iter()
+                .map(|i| (i.location, i.format))
+                .collect::<Vec<_>>(),
+            [
+                (0, wgpu::VertexFormat::Float32x3),
+                (2, wgpu::VertexFormat::Float32x2)
+            ]
+        );
+    }
+
+    #[test]
+    fn vertex_layout_mismatch() {
+        let reflection = ShaderReflection::from_wgsl(SOURCE).unwrap();
+        assert!(reflection
+            .validate_vertex_layouts(&reflection.separate_vertex_layouts())
+            .is_ok());
+
+        let wrong_format = [
+            VertexLayout::single_attribute(wgpu::VertexFormat::Float32x3, 0),
+            VertexLayout::single_attribute(wgpu::VertexFormat::Float32x3, 2),
+        ];
+        assert!(reflection.validate_vertex_layouts(&wrong_format).is_err());
+
+        let missing = [VertexLayout::single_attribute(
+            wgpu::VertexFormat::Float32x3,
+            0,
+        )];
+        assert!(reflection.validate_vertex_layouts(&missing).is_err());
+    }
+
+    #[test]
+    fn bind_group_compatibility() {
+        let reflection = ShaderReflection::from_wgsl(SOURCE).unwrap();
+        let vertex_use = ShaderReflection::from_wgsl(&SOURCE.replace(
+            "vec3<f32>(uv, 0.), 1.0)",
+            "vec3<f32>(uv, 0.), uniforms.intensity)",
+        ))
+        .unwrap();
+        assert_eq!(
+            vertex_use.bind_groups[1][0].visibility,
+            wgpu::ShaderStage::VERTEX | wgpu::ShaderStage::FRAGMENT
+        );
+        assert!(vertex_use.has_compatible_bind_groups(&reflection));
+
+        let renamed =
+            ShaderReflection::from_wgsl(&SOURCE.replace("intensity", "strength")).unwrap();
+        assert!(!renamed.has_compatible_bind_groups(&reflection));
+        let retyped = ShaderReflection::from_wgsl(
+            &SOURCE
+                .replace("intensity: f32", "intensity: vec4<f32>")
+                .replace("* uniforms.intensity", "* uniforms.intensity.x"),
+        )
+        .unwrap();
+        assert!(!retyped.has_compatible_bind_groups(&reflection));
+    }
+}
@@ -0,0 +1,699 @@
+use anyhow::bail;
+use smallvec::SmallVec;
+
+use super::{FrameProfiler, Texture};
+
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Hash)]
+pub struct GraphTextureRef(usize);
+
+/// Texture only living during the execution of a graph
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Hash)]
+pub struct TransientTextureDescriptor {
+    pub width: u32,
+    pub height: u32,
+    pub format: wgpu::TextureFormat,
+}
+
+struct GraphTexture<'a> {
+    name: &'static str,
+    /// Only set for imported textures
+    view: Option<&'a wgpu::TextureView>,
+    /// Only set for transient textures
+    descriptor: Option<TransientTextureDescriptor>,
+}
+
+type PassCallback<'a> = Box<dyn FnOnce(&mut RenderPassContext<'_>) + 'a>;
+
+struct GraphPass<'a> {
+    name: &'static str,
+    reads: SmallVec<[GraphTextureRef; 2]>,
+    color_attachments: SmallVec<[(GraphTextureRef, Option<wgpu::Color>); 2]>,
+    depth_attachment: Option<(GraphTextureRef, Option<f32>)>,
+    /// Set when the pass uses the stencil of its depth attachment, with its clear value
+    stencil: Option<Option<u32>>,
+    execute: Option<PassCallback<'a>>,
+}
+impl GraphPass<'_> {
+    fn writes(&self) -> impl Iterator<Item = GraphTextureRef> + '_ {
+        self.color_attachments
+            .iter()
+            .map(|&(texture, _)| texture)
+            .chain(self.depth_attachment.map(|(texture, _)| texture))
+    }
+    fn uses(&self, texture: GraphTextureRef) -> bool {
+        self.reads.contains(&texture) || self.writes().any(|t| t == texture)
+    }
+}
+
+/// Passes of a frame declaring the textures they read and render to
+///
+/// Passes run after the passes writing the textures they read: the last one added before them,
+/// or all of them when the texture is only written by passes added later. Passes writing the
+/// same texture run in the order they were added, and other passes keep that order when they
+/// don't depend on each other. Passes that don't contribute to an imported texture are skipped,
+/// and transient textures whose uses don't overlap share the same memory.
+pub struct RenderGraph<'a> {
+    textures: Vec<GraphTexture<'a>>,
+    passes: Vec<GraphPass<'a>>,
+    far_depth: f32,
+}
+
+impl<'a> Default for RenderGraph<'a> {
+    fn default() -> Self { Self::new() }
+}
+impl<'a> RenderGraph<'a> {
+    pub fn new() -> Self {
+        Self {
+            textures: Vec::new(),
+            passes: Vec::new(),
+            far_depth: 1.,
+        }
+    }
+    /// Depth that depth attachments without clear value are cleared to, 0 with reverse-Z
+    pub fn set_far_depth(&mut self, depth: f32) { self.far_depth = depth; }
+
+    /// Adds a texture living outside of the graph, its content is loaded and stored by passes
+    pub fn import_texture(
+        &mut self, name: &'static str, view: &'a wgpu::TextureView,
+    ) -> GraphTextureRef {
+        self.textures.push(GraphTexture {
+            name,
+            view: Some(view),
+            descriptor: None,
+        });
+        GraphTextureRef(self.textures.len() - 1)
+    }
+    pub fn create_texture(
+        &mut self, name: &'static str, descriptor: TransientTextureDescriptor,
+    ) -> GraphTextureRef {
+        self.textures.push(GraphTexture {
+            name,
+            view: None,
+            descriptor: Some(descriptor),
+        });
+        GraphTextureRef(self.textures.len() - 1)
+    }
+
+    pub fn add_pass(&mut self, name: &'static str) -> RenderPassBuilder<'_, 'a> {
+        RenderPassBuilder {
+            graph: self,
+            pass: GraphPass {
+                name,
+                reads: SmallVec::new(),
+                color_attachments: SmallVec::new(),
+                depth_attachment: None,
+                stencil: None,
+                execute: None,
+            },
+        }
+    }
+
+    /// Orders the passes, skips the unneeded ones and assigns the transient textures to slots
+    ///
+    /// A pass reading a texture runs after the last pass writing it that was added before it.
+    /// When none was, the read depends on every pass writing the texture, they were all added
+    /// later, so the pass sees the result of the last of them, e.g. a post-processing pass added
+    /// before the scene passes rendering what it reads.
+    fn compile(&self) -> anyhow::Result<CompiledGraph> {
+        let is_imported = |texture: GraphTextureRef| self.textures[texture.0].descriptor.is_none();
+
+        let writers = |texture: GraphTextureRef| {
+            (0..self.passes.len()).filter(move |&j| self.passes[j].writes().any(|t| t == texture))
+        };
+        let previous_writer = |texture: GraphTextureRef, i: usize| {
+            self.passes[..i]
+                .iter()
+                .rposition(|other| other.writes().any(|t| t == texture))
+        };
+        // Reads depend on the last pass writing the texture added before them, or on every pass
+        // writing it when it is only written by passes added later. Writes depend on the
+        // previous write of the texture so passes writing the same texture keep their order.
+        let dependencies = self
+            .passes
+            .iter()
+            .enumerate()
+            .map(|(i, pass)| {
+                let mut dependencies = Vec::new();
+                for &texture in &pass.reads {
+                    match previous_writer(texture, i) {
+                        Some(j) => dependencies.push(j),
+                        None => dependencies.extend(writers(texture)),
+                    }
+                }
+                for texture in pass.writes() {
+                    dependencies.extend(previous_writer(texture, i));
+                }
+                dependencies
+            })
+            .collect::<Vec<_>>();
+
+        // Only keeps the passes contributing to imported textures
+        let mut needed = vec![false; self.passes.len()];
+        let mut stack = (0..self.passes.len())
+            .filter(|&i| self.passes[i].writes().any(is_imported))
+            .collect::<Vec<_>>();
+        while let Some(i) = stack.pop() {
+            if !std::mem::replace(&mut needed[i], true) {
+                stack.extend(&dependencies[i]);
+            }
+        }
+
+        // A write also has to wait for the passes reading the content it overwrites
+        let mut predecessors = dependencies.clone();
+        for (i, pass) in self.passes.iter().enumerate() {
+            if !needed[i] {
+                continue;
+            }
+            for &texture in &pass.reads {
+                if let Some(previous) = previous_writer(texture, i) {
+                    if let Some(next) = writers(texture).find(|&j| j > previous && j != i) {
+                        predecessors[next].push(i);
+                    }
+                }
+            }
+        }
+
+        // Passes are scheduled as soon as their predecessors ran, in the order they were added
+        let mut order = Vec::new();
+        let mut scheduled = vec![false; self.passes.len()];
+        while order.len() < needed.iter().filter(|&&n| n).count() {
+            let next = (0..self.passes.len()).find(|&i| {
+                needed[i]
+                    && !scheduled[i]
+                    && predecessors[i].iter().all(|&d| !needed[d] || scheduled[d])
+            });
+            match next {
+                Some(i) => {
+                    scheduled[i] = true;
+                    order.push(i);
+                }
+                None => bail!(
+                    "The render graph has a cycle between the passes {}",
+                    (0..self.passes.len())
+                        .filter(|&i| needed[i] && !scheduled[i])
+                        .map(|i| self.passes[i].name)
+                        .collect::<Vec<_>>()
+                        .join(", ")
+                ),
+            }
+        }
+
+        let mut ops = (0..self.passes.len())
+            .map(|_| SmallVec::new())
+            .collect::<Vec<_>>();
+        let mut written = (0..self.textures.len())
+            .map(|t| is_imported(GraphTextureRef(t)))
+            .collect::<Vec<_>>();
+        let last_use = |texture: GraphTextureRef| {
+            order
+                .iter()
+                .rposition(|&i| self.passes[i].uses(texture))
+                .unwrap()
+        };
+        for (position, &i) in order.iter().enumerate() {
+            let pass = &self.passes[i];
+            for &texture in &pass.reads {
+                if !written[texture.0] {
+                    bail!(
+                        "Pass {} reads {} which is never written",
+                        pass.name,
+                        self.textures[texture.0].name
+                    );
+                }
+            }
+            let clears = pass
+                .color_attachments
+                .iter()
+                .map(|(_, clear)| clear.is_some())
+                .chain(pass.depth_attachment.map(|(_, clear)| clear.is_some()));
+            for (texture, clear) in pass.writes().zip(clears) {
+                ops[i].push(AttachmentOps {
+                    // The content of transient textures is undefined before their first write
+                    load: !clear && written[texture.0],
+                    store: is_imported(texture) || last_use(texture) > position,
+                });
+                written[texture.0] = true;
+            }
+        }
+
+        // Transient textures are aliased when they have the same description and one is used
+        // after the last use of the other
+        let mut slots = vec![None; self.textures.len()];
+        let mut slot_descriptors = Vec::<TransientSlot>::new();
+        let mut slot_last_uses = Vec::new();
+        for (position, &i) in order.iter().enumerate() {
+            for texture in self.passes[i]
+                .reads
+                .iter()
+                .copied()
+                .chain(self.passes[i].writes())
+            {
+                let descriptor = match self.textures[texture.0].descriptor {
+                    Some(descriptor) => descriptor,
+                    None => continue,
+                };
+                if slots[texture.0].is_some() {
+                    continue;
+                }
+                let usage = order.iter().fold(wgpu::TextureUsage::empty(), |usage, &i| {
+                    let pass = &self.passes[i];
+                    let mut usage = usage;
+                    if pass.reads.contains(&texture) {
+                        usage |= wgpu::TextureUsage::SAMPLED;
+                    }
+                    if pass.writes().any(|t| t == texture) {
+                        usage |= wgpu::TextureUsage::RENDER_ATTACHMENT;
+                    }
+                    usage
+                });
+                let slot = TransientSlot { descriptor, usage };
+                let free = (0..slot_descriptors.len())
+                    .find(|&s| slot_descriptors[s] == slot && slot_last_uses[s] < position);
+                let s = match free {
+                    Some(s) => s,
+                    None => {
+                        slot_descriptors.push(slot);
+                        slot_last_uses.push(0);
+                        slot_descriptors.len() - 1
+                    }
+                };
+                slot_last_uses[s] = last_use(texture);
+                slots[texture.0] = Some(s);
+            }
+        }
+
+        Ok(CompiledGraph {
+            order,
+            ops,
+            slots,
+            slot_descriptors,
+        })
+    }
+
+    /// Records the passes of the graph, transient textures are taken from the pool
+    pub(crate) fn execute(
+        mut self, device: &wgpu::Device, encoder: &mut wgpu::CommandEncoder,
+        pool: &mut TransientTexturePool, profiler: &mut FrameProfiler,
+    ) -> anyhow::Result<()> {
+        let compiled = self.compile()?;
+        pool.allocate(device, &compiled.slot_descriptors);
+        let far_depth = self.far_depth;
+
+        let views = self
+            .textures
+            .iter()
+            .zip(&compiled.slots)
+            .map(|(texture, slot)| match texture.view {
+                Some(view) => view,
+                None => &pool.textures[slot.unwrap()].1.view,
+            })
+            .collect::<Vec<_>>();
+
+        for &i in &compiled.order {
+            let pass = &mut self.passes[i];
+            let mut ops = compiled.ops[i].iter();
+            let color_attachments = pass
+                .color_attachments
+                .iter()
+                .zip(&mut ops)
+                .map(|(&(texture, clear), ops)| wgpu::RenderPassColorAttachment {
+                    view: views[texture.0],
+                    resolve_target: None,
+                    ops: wgpu::Operations {
+                        load: if ops.load {
+                            wgpu::LoadOp::Load
+                        }
+                        else {
+                            wgpu::LoadOp::Clear(clear.unwrap_or(wgpu::Color::BLACK))
+                        },
+                        store: ops.store,
+                    },
+                })
+                .collect();
+            let stencil = pass.stencil;
+            let depth_stencil_attachment =
+                pass.depth_attachment
+                    .zip(ops.next())
+                    .map(
+                        |((texture, clear), ops)| wgpu::RenderPassDepthStencilAttachment {
+                            view: views[texture.0],
+                            depth_ops: Some(wgpu::Operations {
+                                load: if ops.load {
+                                    wgpu::LoadOp::Load
+                                }
+                                else {
+                                    wgpu::LoadOp::Clear(clear.unwrap_or(far_depth))
+                                },
+                                store: ops.store,
+                            }),
+                            stencil_ops: stencil.map(|clear| wgpu::Operations {
+                                load: if ops.load {
+                                    wgpu::LoadOp::Load
+                                }
+                                else {
+                                    wgpu::LoadOp::Clear(clear.unwrap_or(0))
+                                },
+                                store: ops.store,
+                            }),
+                        },
+                    );
+
+            profiler.begin_scope(pass.name, encoder);
+            if let Some(execute) = pass.execute.take() {
+                execute(&mut RenderPassContext {
+                    encoder,
+                    label: pass.name,
+                    color_attachments,
+                    depth_stencil_attachment,
+                    views: &views,
+                });
+            }
+            profiler.end_scope(encoder);
+        }
+
+        Ok(())
+    }
+}
+
+pub struct RenderPassBuilder<'g, 'a> {
+    graph: &'g mut RenderGraph<'a>,
+    pass: GraphPass<'a>,
+}
+impl<'g, 'a> RenderPassBuilder<'g, 'a> {
+    /// Declares a texture sampled by the pass, see [`RenderPassContext::texture_view`]
+    pub fn read(mut self, texture: GraphTextureRef) -> Self {
+        self.pass.reads.push(texture);
+        self
+    }
+    /// Renders to the texture, clearing it first if a color is given
+    pub fn color_attachment(
+        mut self, texture: GraphTextureRef, clear: Option<wgpu::Color>,
+    ) -> Self {
+        self.pass.color_attachments.push((texture, clear));
+        self
+    }
+    pub fn depth_attachment(mut self, texture: GraphTextureRef, clear: Option<f32>) -> Self {
+        self.pass.depth_attachment = Some((texture, clear));
+        self
+    }
+    /// Makes the stencil of the depth attachment writable, it is loaded and stored with the depth
+    /// and cleared with it to the given value or 0
+    ///
+    /// Without it the stencil of depth-stencil formats is read-only.
+    pub fn stencil(mut self, clear: Option<u32>) -> Self {
+        self.pass.stencil = Some(clear);
+        self
+    }
+    /// Adds the pass to the graph, the callback records it when the graph is executed
+    pub fn execute(mut self, execute: impl FnOnce(&mut RenderPassContext<'_>) + 'a) {
+        self.pass.execute = Some(Box::new(execute));
+        self.graph.passes.push(self.pass);
+    }
+}
+
+/// Given to passes when they are recorded
+pub struct RenderPassContext<'p> {
+    pub encoder: &'p mut wgpu::CommandEncoder,
+    label: &'static str,
+    color_attachments: SmallVec<[wgpu::RenderPassColorAttachment<'p>; 2]>,
+    depth_stencil_attachment: Option<wgpu::RenderPassDepthStencilAttachment<'p>>,
+    views: &'p [&'p wgpu::TextureView],
+}
+impl<'p> RenderPassContext<'p> {
+    /// Begins a render pass on the attachments declared by the pass
+    pub fn begin_render_pass(&mut self) -> wgpu::RenderPass<'_> {
+        self.encoder.begin_render_pass(&wgpu::RenderPassDescriptor {
+            label: Some(self.label),
+            color_attachments: &self.color_attachments,
+            depth_stencil_attachment: self.depth_stencil_attachment.clone(),
+        })
+    }
+    pub fn texture_view(&self, texture: GraphTextureRef) -> &'p wgpu::TextureView {
+        self.views[texture.0]
+    }
+}
+
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+struct AttachmentOps {
+    /// Whether the previous content is loaded, the attachment is cleared otherwise
+    load: bool,
+    store: bool,
+}
+
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+struct TransientSlot {
+    descriptor: TransientTextureDescriptor,
+    usage: wgpu::TextureUsage,
+}
+
+struct CompiledGraph {
+    /// Passes to execute in order, skipped passes aren't included
+    order: Vec<usize>,
+    /// Operations of each pass's color attachments followed by its depth attachment
+    ops: Vec<SmallVec<[AttachmentOps; 3]>>,
+    /// Slot of the pool holding each transient texture
+    slots: Vec<Option<usize>>,
+    slot_descriptors: Vec<TransientSlot>,
+}
+
+/// Textures backing the transient textures of render graphs, kept between frames
+#[derive(Default)]
+pub(crate) struct TransientTexturePool {
+    textures: Vec<(TransientSlot, Texture)>,
+}
+impl TransientTexturePool {
+    fn allocate(&mut self, device: &wgpu::Device, slots: &[TransientSlot]) {
+        let mut previous = std::mem::take(&mut self.textures);
+        self.textures = slots
+            .iter()
+            .map(|&slot| {
+                let texture = match previous.iter().position(|(s, _)| *s == slot) {
+                    Some(i) => previous.swap_remove(i).1,
+                    None => Self::create_texture(device, slot),
+                };
+                (slot, texture)
+            })
+            .collect();
+    }
+    fn create_texture(device: &wgpu::Device, slot: TransientSlot) -> Texture {
+        let texture = device.create_texture(&wgpu::TextureDescriptor {
+            label: Some("Transient texture"),
+            size: wgpu::Extent3d {
+                width: slot.descriptor.width,
+                height: slot.descriptor.height,
+                depth_or_array_layers: 1,
+            },
+            mip_level_count: 1,
+            sample_count: 1,
+            dimension: wgpu::TextureDimension::D2,
+            format: slot.descriptor.format,
+            usage: slot.usage,
+        });
+        let view = texture.create_view(&wgpu::TextureViewDescriptor::default());
+        Texture {
+            texture,
+            view,
+            sampler: None,
+            memory_size: slot.descriptor.width as u64
+                * slot.descriptor.height as u64
+                * slot.descriptor.format.describe().block_size as u64,
+        }
+    }
+
+    pub(crate) fn memory_size(&self) -> u64 {
+        self.textures
+            .iter()
+            .map(|(_, texture)| texture.memory_size)
+            .sum()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const DESCRIPTOR: TransientTextureDescriptor = TransientTextureDescriptor {
+        width: 64,
+        height: 64,
+        format: wgpu::TextureFormat::Rgba16Float,
+    };
+
+    /// Compilation doesn't look at the views of imported textures
+    fn import_texture(graph: &mut RenderGraph, name: &'static str) -> GraphTextureRef {
+        graph.textures.push(GraphTexture {
+            name,
+            view: None,
+            descriptor: None,
+        });
+        GraphTextureRef(graph.textures.len() - 1)
+    }
+    fn add_pass(
+        graph: &mut RenderGraph, name: &'static str, reads: &[GraphTextureRef],
+        writes: &[GraphTextureRef],
+    ) {
+        let mut builder = graph.add_pass(name);
+        for &texture in reads {
+            builder = builder.read(texture);
+        }
+        for &texture in writes {
+            builder = builder.color_attachment(texture, None);
+        }
+        builder.execute(|_| ());
+    }
+    fn pass_names(graph: &RenderGraph, compiled: &CompiledGraph) -> Vec<&'static str> {
+        compiled
+            .order
+            .iter()
+            .map(|&i| graph.passes[i].name)
+            .collect()
+    }
+
+    #[test]
+    fn orders_and_culls_passes() {
+        let mut graph = RenderGraph::new();
+        let output = import_texture(&mut graph, "output");
+        let scene = graph.create_texture("scene", DESCRIPTOR);
+        let unused = graph.create_texture("unused", DESCRIPTOR);
+        add_pass(&mut graph, "post", &[scene], &[output]);
+        add_pass(&mut graph, "scene", &[], &[scene]);
+        add_pass(&mut graph, "debug", &[], &[unused]);
+        add_pass(&mut graph, "ui", &[], &[output]);
+
+        let compiled = graph.compile().unwrap();
+        assert_eq!(pass_names(&graph, &compiled), &["scene", "post", "ui"]);
+    }
+
+    #[test]
+    fn reads_see_the_previous_write() {
+        let mut graph = RenderGraph::new();
+        let output = import_texture(&mut graph, "output");
+        let a = graph.create_texture("a", DESCRIPTOR);
+        let b = graph.create_texture("b", DESCRIPTOR);
+        add_pass(&mut graph, "write a", &[], &[a]);
+        add_pass(&mut graph, "a to b", &[a], &[b]);
+        add_pass(&mut graph, "rewrite a", &[], &[a]);
+        add_pass(&mut graph, "a and b to output", &[a, b], &[output]);
+
+        let compiled = graph.compile().unwrap();
+        assert_eq!(pass_names(&graph, &compiled), &[
+            "write a",
+            "a to b",
+            "rewrite a",
+            "a and b to output"
+        ]);
+        // a is read again after being rewritten
+        assert!(compiled.ops[0][0].store);
+        assert!(compiled.ops[2][0].store);
+
+        // The rewrite waits for the read moved after a pass added later
+        let mut graph = RenderGraph::new();
+        let output = import_texture(&mut graph, "output");
+        let a = graph.create_texture("a", DESCRIPTOR);
+        let b = graph.create_texture("b", DESCRIPTOR);
+        let c = graph.create_texture("c", DESCRIPTOR);
+        add_pass(&mut graph, "write a", &[], &[a]);
+        add_pass(&mut graph, "a and c to b", &[a, c], &[b]);
+        add_pass(&mut graph, "rewrite a", &[], &[a]);
+        add_pass(&mut graph, "write c", &[], &[c]);
+        add_pass(&mut graph, "a and b to output", &[a, b], &[output]);
+
+        let compiled = graph.compile().unwrap();
+        assert_eq!(pass_names(&graph, &compiled), &[
+            "write a",
+            "write c",
+            "a and c to b",
+            "rewrite a",
+            "a and b to output"
+        ]);
+    }
+
+    #[test]
+    fn reads_before_writes_wait_for_every_writer() {
+        let mut graph = RenderGraph::new();
+        let output = import_texture(&mut graph, "output");
+        let scene = graph.create_texture("scene", DESCRIPTOR);
+        add_pass(&mut graph, "post", &[scene], &[output]);
+        add_pass(&mut graph, "opaque", &[], &[scene]);
+        add_pass(&mut graph, "transparent", &[], &[scene]);
+
+        let compiled = graph.compile().unwrap();
+        assert_eq!(pass_names(&graph, &compiled), &[
+            "opaque",
+            "transparent",
+            "post"
+        ]);
+        // The second write keeps the first one's content
+        assert!(compiled.ops[2][0].load);
+    }
+
+    #[test]
+    fn load_store_and_aliasing() {
+        let mut graph = RenderGraph::new();
+        let output = import_texture(&mut graph, "output");
+        let a = graph.create_texture("a", DESCRIPTOR);
+        let b = graph.create_texture("b", DESCRIPTOR);
+        let c = graph.create_texture("c", DESCRIPTOR);
+        add_pass(&mut graph, "write a", &[], &[a]);
+        add_pass(&mut graph, "a to b", &[a], &[b]);
+        add_pass(&mut graph, "b to c", &[b], &[c]);
+        add_pass(&mut graph, "c to output", &[c], &[output]);
+
+        let compiled = graph.compile().unwrap();
+        let cleared_and_stored = AttachmentOps {
+            load: false,
+            store: true,
+        };
+        assert_eq!(compiled.ops[0].as_slice(), &[cleared_and_stored]);
+        assert_eq!(compiled.ops[2].as_slice(), &[cleared_and_stored]);
+        assert_eq!(compiled.ops[3].as_slice(), &[AttachmentOps {
+            load: true,
+            store: true,
+        }]);
+
+        // a is dead once c is written
+        assert_eq!(compiled.slots, &[None, Some(0), Some(1), Some(0)]);
+        assert_eq!(compiled.slot_descriptors.len(), 2);
+    }
+
+    #[test]
+    fn unused_results_are_not_stored() {
+        let mut graph = RenderGraph::new();
+        let output = import_texture(&mut graph, "output");
+        let depth = graph.create_texture("depth", DESCRIPTOR);
+        graph
+            .add_pass("scene")
+            .color_attachment(output, None)
+            .depth_attachment(depth, Some(1.))
+            .execute(|_| ());
+
+        let compiled = graph.compile().unwrap();
+        assert_eq!(compiled.ops[0].as_slice(), &[
+            AttachmentOps {
+                load: true,
+                store: true,
+            },
+            AttachmentOps {
+                load: false,
+                store: false,
+            },
+        ]);
+    }
+
+    #[test]
+    fn invalid_graphs() {
+        let mut graph = RenderGraph::new();
+        let output = import_texture(&mut graph, "output");
+        let x = graph.create_texture("x", DESCRIPTOR);
+        let y = graph.create_texture("y", DESCRIPTOR);
+        add_pass(&mut graph, "x to y", &[x], &[y]);
+        add_pass(&mut graph, "y to x", &[y], &[x]);
+        add_pass(&mut graph, "y to output", &[y], &[output]);
+        assert!(graph.compile().is_err());
+
+        let mut graph = RenderGraph::new();
+        let output = import_texture(&mut graph, "output");
+        let never_written = graph.create_texture("never written", DESCRIPTOR);
+        add_pass(&mut graph, "pass", &[never_written], &[output]);
+        assert!(graph.compile().is_err());
+    }
+}
@@ -1,15 +1,62 @@
-use std::marker::PhantomData;
+use std::{
+    marker::PhantomData,
+    path::{Path, PathBuf},
+    time::SystemTime,
+};
 
 use smallvec::SmallVec;
 
+use super::{ShaderReflection, VertexLayout};
+
 pub struct Shader {
-    pub vertex_shader_module: wgpu::ShaderModule,
-    pub fragment_shader_module: wgpu::ShaderModule,
+    pub module: wgpu::ShaderModule,
     pub render_pipeline_layout: wgpu::PipelineLayout,
+    /// Layouts of the groups following the renderer's uniforms at group 0
     pub bind_group_layouts: SmallVec<[wgpu::BindGroupLayout; 2]>,
-    pub vertex_group_layouts: SmallVec<[wgpu::VertexBufferLayout<'static>; 2]>,
+    pub vertex_layouts: SmallVec<[VertexLayout; 4]>,
+    pub reflection: ShaderReflection,
+    /// Source before preprocessing, see [`preprocess_wgsl`](super::preprocess_wgsl)
+    pub source: String,
+    /// Sorted defines this shader was preprocessed with
+    pub defines: Vec<String>,
+    /// Set for shaders created with [`Renderer::create_shader_from_file`](super::Renderer::create_shader_from_file),
+    /// which are reloaded by [`Renderer::reload_modified_shaders`](super::Renderer::reload_modified_shaders)
+    pub source_file: Option<ShaderSourceFile>,
 
     pub(crate) marker: PhantomData<()>,
 }
-#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Hash)]
 pub struct ShaderRef(pub(crate) usize);
+
+#[derive(Debug, Clone)]
+pub struct ShaderSourceFile {
+    pub path: PathBuf,
+    pub last_modified: Option<SystemTime>,
+}
+impl ShaderSourceFile {
+    pub(crate) fn new(path: PathBuf) -> Self {
+        let last_modified = Self::read_modified_time(&path);
+        Self {
+            path,
+            last_modified,
+        }
+    }
+
+    fn read_modified_time(path: &Path) -> Option<SystemTime> {
+        std::fs::metadata(path).and_then(|m| m.modified()).ok()
+    }
+
+    /// Updates the stored modification time, returns true if it changed since the last call
+    pub(crate) fn poll_modified(&mut self) -> bool {
+        let last_modified = Self::read_modified_time(&self.path);
+        if last_modified == self.last_modified {
+            return false;
+        }
+        self.last_modified = last_modified;
+        true
+    }
+
+    pub(crate) fn read_source(&self) -> anyhow::Result<String> {
+        Ok(std::fs::read_to_string(&self.path)?)
+    }
+}
@@ -0,0 +1,4 @@
+// Lambert term remapped from [-1, 1] to [0, 1] so that unlit sides aren't completely black
+fn half_lambert(normal: vec3<f32>, light_direction: vec3<f32>) -> f32 {
+    return (dot(normal, light_direction) + 1.) / 2.;
+}
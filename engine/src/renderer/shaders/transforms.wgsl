@@ -0,0 +1,9 @@
+#include "view_uniforms"
+
+fn model_to_world(position: vec3<f32>) -> vec4<f32> {
+    return render_uniforms.model_matrix * vec4<f32>(position, 1.0);
+}
+
+fn model_to_clip(position: vec3<f32>) -> vec4<f32> {
+    return render_uniforms.view_projection * model_to_world(position);
+}
@@ -0,0 +1,6 @@
+[[block]] struct RenderUniforms {
+    view_projection: mat4x4<f32>;
+    model_matrix: mat4x4<f32>;
+};
+[[group(0), binding(0)]]
+var<uniform> render_uniforms: RenderUniforms;
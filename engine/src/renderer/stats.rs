@@ -0,0 +1,138 @@
+use std::ops::Range;
+
+use super::{MaterialRef, PipelineKey};
+
+/// Counters of the last frame rendered by [`Renderer::render_camera`](super::Renderer::render_camera)
+/// and the memory currently used by the renderer's resources
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct RenderStats {
+    pub draw_calls: u32,
+    pub indices: u64,
+    pub triangles: u64,
+    /// Meshes with at least one draw call
+    pub drawn_meshes: u32,
+    pub pipeline_changes: u32,
+    pub bind_group_changes: u32,
+
+    /// Size in bytes of the mesh, material and uniform buffers
+    pub buffer_memory: u64,
+    /// Size in bytes of the registered textures and of the render graph's transient textures,
+    /// the depth buffer among them
+    pub texture_memory: u64,
+}
+impl RenderStats {
+    /// Counters of a frame issuing the draws in order, see [`with_state_changes`]
+    pub(crate) fn from_draws(draws: &[DrawRecord]) -> Self {
+        let mut stats = Self::default();
+        for (draw, mesh_changed, pipeline_changed) in with_state_changes(draws) {
+            if mesh_changed {
+                // The render uniforms are bound at the mesh's offset
+                stats.drawn_meshes += 1;
+                stats.bind_group_changes += 1;
+            }
+            if pipeline_changed {
+                stats.pipeline_changes += 1;
+                stats.bind_group_changes += draw.material_bind_groups;
+            }
+            let index_count = draw.indices.end - draw.indices.start;
+            stats.draw_calls += 1;
+            stats.indices += index_count as u64;
+            stats.triangles += triangle_count(draw.pipeline_key.topology, index_count) as u64;
+        }
+        stats
+    }
+
+    /// Sets the memory counters to the sums of the buffer and texture sizes
+    pub(crate) fn with_memory(
+        mut self, buffers: impl IntoIterator<Item = u64>, textures: impl IntoIterator<Item = u64>,
+    ) -> Self {
+        self.buffer_memory = buffers.into_iter().sum();
+        self.texture_memory = textures.into_iter().sum();
+        self
+    }
+}
+
+/// Submesh draw of a frame, meshes with no material or pipeline for a slot don't record it
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct DrawRecord {
+    /// Index of the mesh in the frame, the draws of a mesh are consecutive
+    pub mesh: usize,
+    pub material: MaterialRef,
+    pub pipeline_key: PipelineKey,
+    /// Number of bind groups of the material, following the render uniforms
+    pub material_bind_groups: u32,
+    pub indices: Range<u32>,
+}
+
+/// Draws along with whether they use another mesh and another pipeline than the previous one,
+/// which is when the render loop binds them
+pub(crate) fn with_state_changes(
+    draws: &[DrawRecord],
+) -> impl Iterator<Item = (&DrawRecord, bool, bool)> {
+    let mut last_mesh = None;
+    let mut last_pipeline = None;
+    draws.iter().map(move |draw| {
+        let mesh_changed = last_mesh.replace(draw.mesh) != Some(draw.mesh);
+        let pipeline = (draw.material, draw.pipeline_key);
+        let pipeline_changed = last_pipeline.replace(pipeline) != Some(pipeline);
+        (draw, mesh_changed, pipeline_changed)
+    })
+}
+
+/// Number of triangles drawn by the given number of indices, ignoring strip restarts
+pub(crate) fn triangle_count(topology: wgpu::PrimitiveTopology, indices: u32) -> u32 {
+    match topology {
+        wgpu::PrimitiveTopology::TriangleList => indices / 3,
+        wgpu::PrimitiveTopology::TriangleStrip => indices.saturating_sub(2),
+        _ => 0,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn triangle_counts() {
+        assert_eq!(triangle_count(wgpu::PrimitiveTopology::TriangleList, 9), 3);
+        assert_eq!(triangle_count(wgpu::PrimitiveTopology::TriangleStrip, 5), 3);
+        assert_eq!(triangle_count(wgpu::PrimitiveTopology::TriangleStrip, 1), 0);
+        assert_eq!(triangle_count(wgpu::PrimitiveTopology::LineList, 6), 0);
+    }
+
+    #[test]
+    fn frame_stats() {
+        let lines = PipelineKey {
+            topology: wgpu::PrimitiveTopology::LineList,
+            strip_index_format: None,
+        };
+        let draw = |mesh, material, pipeline_key, indices| DrawRecord {
+            mesh,
+            material: MaterialRef(material),
+            pipeline_key,
+            material_bind_groups: 2,
+            indices,
+        };
+        // Two submeshes of the first mesh with different materials, then two meshes sharing
+        // the last material, the second one drawn as lines
+        let draws = [
+            draw(0, 0, PipelineKey::default(), 0..6),
+            draw(0, 1, PipelineKey::default(), 6..9),
+            draw(1, 1, PipelineKey::default(), 0..3),
+            draw(2, 1, lines, 0..4),
+        ];
+        let stats = RenderStats::from_draws(&draws).with_memory(vec![256, 64], vec![1024]);
+        assert_eq!(stats, RenderStats {
+            draw_calls: 4,
+            indices: 16,
+            triangles: 4,
+            drawn_meshes: 3,
+            pipeline_changes: 3,
+            bind_group_changes: 3 + 3 * 2,
+            buffer_memory: 320,
+            texture_memory: 1024,
+        });
+
+        assert_eq!(RenderStats::from_draws(&[]), RenderStats::default());
+    }
+}
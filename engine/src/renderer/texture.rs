@@ -7,6 +7,8 @@ pub struct Texture {
     pub texture: wgpu::Texture,
     pub view: wgpu::TextureView,
     pub sampler: Option<wgpu::Sampler>,
+    /// Size of the texture's data in bytes
+    pub memory_size: u64,
 }
 
 impl Texture {
@@ -39,6 +41,7 @@ impl Texture {
             texture,
             view,
             sampler: None,
+            memory_size: image.as_bytes().len() as u64,
         }
     }
     pub fn create_sampler(
@@ -56,3 +59,5 @@ impl Texture {
         }));
     }
 }
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub struct TextureRef(pub(crate) usize);
@@ -0,0 +1,142 @@
+use bytemuck::Pod;
+pub use portal_engine_derive::Vertex;
+use smallvec::SmallVec;
+
+/// A vertex type that can be uploaded as a single interleaved vertex buffer
+///
+/// Usually derived with `#[derive(Vertex)]`, see [`portal_engine_derive::Vertex`]
+pub trait Vertex: Pod {
+    fn vertex_layout() -> VertexLayout;
+}
+
+/// Types that can be used as a vertex attribute
+pub trait VertexAttributeType: Pod {
+    const FORMAT: wgpu::VertexFormat;
+}
+macro_rules! impl_vertex_attribute_type {
+    ($($ty:ty => $format:ident),* $(,)?) => {$(
+        impl VertexAttributeType for $ty {
+            const FORMAT: wgpu::VertexFormat = wgpu::VertexFormat::$format;
+        }
+    )*};
+}
+impl_vertex_attribute_type! {
+    f32 => Float32,
+    [f32; 2] => Float32x2,
+    [f32; 3] => Float32x3,
+    [f32; 4] => Float32x4,
+    u32 => Uint32,
+    [u32; 2] => Uint32x2,
+    [u32; 3] => Uint32x3,
+    [u32; 4] => Uint32x4,
+    i32 => Sint32,
+    [i32; 2] => Sint32x2,
+    [i32; 3] => Sint32x3,
+    [i32; 4] => Sint32x4,
+    [u8; 2] => Uint8x2,
+    [u8; 4] => Uint8x4,
+    [u16; 2] => Uint16x2,
+    [u16; 4] => Uint16x4,
+}
+
+/// Owned version of [`wgpu::VertexBufferLayout`], so layouts can be built at runtime
+#[derive(Debug, Clone, PartialEq)]
+pub struct VertexLayout {
+    pub array_stride: wgpu::BufferAddress,
+    pub step_mode: wgpu::InputStepMode,
+    pub attributes: SmallVec<[wgpu::VertexAttribute; 4]>,
+}
+impl VertexLayout {
+    /// Layout of a buffer containing a single tightly packed attribute
+    pub fn single_attribute(format: wgpu::VertexFormat, shader_location: u32) -> Self {
+        Self {
+            array_stride: format.size(),
+            step_mode: wgpu::InputStepMode::Vertex,
+            attributes: std::iter::once(wgpu::VertexAttribute {
+                format,
+                offset: 0,
+                shader_location,
+            })
+            .collect(),
+        }
+    }
+
+    /// Layout of a buffer of interleaved vertices, see [`VertexLayout::attribute`]
+    pub fn interleaved(
+        array_stride: wgpu::BufferAddress, attributes: &[wgpu::VertexAttribute],
+    ) -> Self {
+        Self {
+            array_stride,
+            step_mode: wgpu::InputStepMode::Vertex,
+            attributes: attributes.into(),
+        }
+    }
+    pub fn attribute<T: VertexAttributeType>(
+        offset: wgpu::BufferAddress, shader_location: u32,
+    ) -> wgpu::VertexAttribute {
+        wgpu::VertexAttribute {
+            format: T::FORMAT,
+            offset,
+            shader_location,
+        }
+    }
+
+    pub fn as_wgpu(&self) -> wgpu::VertexBufferLayout<'_> {
+        wgpu::VertexBufferLayout {
+            array_stride: self.array_stride,
+            step_mode: self.step_mode,
+            attributes: &self.attributes,
+        }
+    }
+}
+impl From<&wgpu::VertexBufferLayout<'_>> for VertexLayout {
+    fn from(layout: &wgpu::VertexBufferLayout) -> Self {
+        Self {
+            array_stride: layout.array_stride,
+            step_mode: layout.step_mode,
+            attributes: layout.attributes.into(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use bytemuck::Zeroable;
+
+    use super::*;
+
+    #[repr(C)]
+    #[derive(Clone, Copy, Zeroable, Pod, Vertex)]
+    struct TestVertex {
+        position: [f32; 3],
+        #[vertex(skip)]
+        _padding: f32,
+        #[vertex(location = 3)]
+        uv: [f32; 2],
+        color: [u8; 4],
+    }
+
+    #[test]
+    fn derived_layout() {
+        let layout = TestVertex::vertex_layout();
+        assert_eq!(layout.array_stride, 28);
+        assert_eq!(layout.step_mode, wgpu::InputStepMode::Vertex);
+        assert_eq!(layout.attributes.as_slice(), &[
+            wgpu::VertexAttribute {
+                format: wgpu::VertexFormat::Float32x3,
+                offset: 0,
+                shader_location: 0,
+            },
+            wgpu::VertexAttribute {
+                format: wgpu::VertexFormat::Float32x2,
+                offset: 16,
+                shader_location: 3,
+            },
+            wgpu::VertexAttribute {
+                format: wgpu::VertexFormat::Uint8x4,
+                offset: 24,
+                shader_location: 4,
+            },
+        ]);
+    }
+}
@@ -0,0 +1,633 @@
+use std::{
+    collections::{BTreeMap, HashMap, HashSet},
+    fs,
+    path::{Path, PathBuf},
+};
+
+use anyhow::{anyhow, bail, Context};
+use nalgebra::{Orthographic3, Perspective3};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+
+use crate::{
+    camera::{
+        CameraComponent,
+        CameraMatrix,
+        OrthographicCameraMatrix,
+        PerspectiveCameraMatrix,
+        PerspectiveDepth,
+    },
+    hecs_extension::*,
+    renderer::MeshComponent,
+    transform::TransformComponent,
+};
+
+/// Version written in saved scenes, files from newer versions are rejected
+pub const SCENE_VERSION: u32 = 1;
+
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum SceneFormat {
+    Ron,
+    Json,
+}
+impl SceneFormat {
+    /// Format matching the extension of the path, `.ron` or `.json`
+    pub fn from_path(path: &Path) -> Option<Self> {
+        match path.extension()?.to_str()? {
+            "ron" => Some(Self::Ron),
+            "json" => Some(Self::Json),
+            _ => None,
+        }
+    }
+}
+
+/// Files of the mesh of an entity and of its material overrides
+///
+/// Renderer resources can't be saved, scenes keep this component instead and
+/// [`resolve_mesh_assets`] turns it into a [`MeshComponent`] once loaded.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct MeshAssetComponent {
+    pub mesh: PathBuf,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub material_overrides: Vec<Option<PathBuf>>,
+}
+
+/// Component saved in scenes under a name unique among the registered components
+pub trait SceneComponent: hecs::Component + Serialize + DeserializeOwned {
+    const NAME: &'static str;
+}
+
+type SaveFn = fn(&hecs::World, hecs::Entity) -> Option<anyhow::Result<serde_json::Value>>;
+type LoadFn = fn(&mut hecs::World, hecs::Entity, serde_json::Value) -> anyhow::Result<()>;
+
+struct RegisteredComponent {
+    name: &'static str,
+    contains: fn(&hecs::World, hecs::Entity) -> bool,
+    save: SaveFn,
+    load: LoadFn,
+}
+
+/// User components saved and loaded along the engine's ones
+#[derive(Default)]
+pub struct SceneRegistry {
+    components: Vec<RegisteredComponent>,
+}
+impl SceneRegistry {
+    pub fn new() -> Self { Self::default() }
+
+    pub fn register<T: SceneComponent>(&mut self) -> &mut Self {
+        assert!(
+            self.components.iter().all(|c| c.name != T::NAME),
+            "scene component {} is registered twice",
+            T::NAME
+        );
+        self.components.push(RegisteredComponent {
+            name: T::NAME,
+            contains: |world, entity| world.get::<T>(entity).is_ok(),
+            save: |world, entity| {
+                let component = world.get::<T>(entity).ok()?;
+                Some(serde_json::to_value(&*component).map_err(Into::into))
+            },
+            load: |world, entity, value| {
+                let component = serde_json::from_value::<T>(value)?;
+                world.insert_one(entity, component)?;
+                Ok(())
+            },
+        });
+        self
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub enum SceneProjection {
+    Perspective {
+        aspect: f32,
+        fovy: f32,
+        znear: f32,
+        zfar: f32,
+        depth: PerspectiveDepth,
+    },
+    Orthographic {
+        left: f32,
+        right: f32,
+        bottom: f32,
+        top: f32,
+        znear: f32,
+        zfar: f32,
+    },
+}
+
+/// Settings of a [`CameraComponent`], only perspective and orthographic projections can be saved
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct SceneCamera {
+    /// RGBA
+    pub clear_color: Option<[f64; 4]>,
+    pub projection: SceneProjection,
+    pub is_enabled: bool,
+}
+impl SceneCamera {
+    fn from_component(camera: &CameraComponent) -> Option<Self> {
+        let projection = if let Some(p) = camera.matrix.downcast_ref::<PerspectiveCameraMatrix>() {
+            SceneProjection::Perspective {
+                aspect: p.0.aspect(),
+                fovy: p.0.fovy(),
+                znear: p.0.znear(),
+                zfar: p.0.zfar(),
+                depth: p.1,
+            }
+        }
+        else if let Some(o) = camera.matrix.downcast_ref::<OrthographicCameraMatrix>() {
+            SceneProjection::Orthographic {
+                left: o.0.left(),
+                right: o.0.right(),
+                bottom: o.0.bottom(),
+                top: o.0.top(),
+                znear: o.0.znear(),
+                zfar: o.0.zfar(),
+            }
+        }
+        else {
+            return None;
+        };
+        Some(Self {
+            clear_color: camera.clear_color.map(|c| [c.r, c.g, c.b, c.a]),
+            projection,
+            is_enabled: camera.is_enabled,
+        })
+    }
+
+    fn to_component(self) -> CameraComponent {
+        let matrix: Box<dyn CameraMatrix> = match self.projection {
+            SceneProjection::Perspective {
+                aspect,
+                fovy,
+                znear,
+                zfar,
+                depth,
+            } => Box::new(PerspectiveCameraMatrix(
+                Perspective3::new(aspect, fovy, znear, zfar),
+                depth,
+            )),
+            SceneProjection::Orthographic {
+                left,
+                right,
+                bottom,
+                top,
+                znear,
+                zfar,
+            } => Box::new(OrthographicCameraMatrix(Orthographic3::new(
+                left, right, bottom, top, znear, zfar,
+            ))),
+        };
+        CameraComponent {
+            clear_color: self
+                .clear_color
+                .map(|[r, g, b, a]| wgpu::Color { r, g, b, a }),
+            matrix,
+            is_enabled: self.is_enabled,
+        }
+    }
+}
+
+/// Saved entity, `id` is only meaningful inside its scene and is remapped on load
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct SceneEntity {
+    pub id: u64,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub parent: Option<u64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub transform: Option<TransformComponent>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub camera: Option<SceneCamera>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub mesh: Option<MeshAssetComponent>,
+    /// Registered user components by name
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub components: BTreeMap<String, serde_json::Value>,
+}
+
+/// Entities are stored parents first, children in their hierarchy order
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Scene {
+    pub version: u32,
+    pub entities: Vec<SceneEntity>,
+}
+impl Scene {
+    /// Captures the entities that have a transform, a camera, a mesh, a registered component or
+    /// that are part of a hierarchy
+    ///
+    /// Meshes are saved from their [`MeshAssetComponent`], entities with a [`MeshComponent`] but
+    /// no asset are an error.
+    pub fn from_world(world: &hecs::World, registry: &SceneRegistry) -> anyhow::Result<Self> {
+        let saved = world
+            .iter()
+            .map(|(entity, _)| entity)
+            .filter(|&entity| {
+                world.get::<TransformComponent>(entity).is_ok()
+                    || world.get::<CameraComponent>(entity).is_ok()
+                    || world.get::<MeshAssetComponent>(entity).is_ok()
+                    || world.get::<MeshComponent>(entity).is_ok()
+                    || world.get::<ParentComponent>(entity).is_ok()
+                    || world.get::<ChildrenComponent>(entity).is_ok()
+                    || registry
+                        .components
+                        .iter()
+                        .any(|c| (c.contains)(world, entity))
+            })
+            .collect::<HashSet<_>>();
+
+        let parent_of = |entity| {
+            world
+                .get::<ParentComponent>(entity)
+                .ok()
+                .and_then(|parent| parent.0)
+                .filter(|parent| saved.contains(parent))
+        };
+        // Parent links are followed rather than children lists, which may be inconsistent.
+        // Children keep the order their parent lists them in.
+        let mut roots = Vec::new();
+        let mut children = HashMap::<_, Vec<_>>::new();
+        for &entity in &saved {
+            match parent_of(entity) {
+                Some(parent) => children.entry(parent).or_default().push(entity),
+                None => roots.push(entity),
+            }
+        }
+        roots.sort();
+        for (parent, children) in &mut children {
+            let listed = world.get::<ChildrenComponent>(*parent).ok();
+            children.sort_by_key(|child| {
+                let position = listed
+                    .as_ref()
+                    .and_then(|listed| listed.0.iter().position(|c| c == child));
+                (position.unwrap_or(usize::MAX), *child)
+            });
+        }
+
+        let mut order = Vec::with_capacity(saved.len());
+        let mut stack = roots.into_iter().rev().collect::<Vec<_>>();
+        while let Some(entity) = stack.pop() {
+            order.push(entity);
+            stack.extend(children.get(&entity).into_iter().flatten().rev());
+        }
+        if order.len() < saved.len() {
+            let mut in_cycles = saved
+                .iter()
+                .filter(|entity| !order.contains(entity))
+                .collect::<Vec<_>>();
+            in_cycles.sort();
+            bail!("The parents of {:?} form a cycle", in_cycles);
+        }
+
+        let mut ids = HashMap::new();
+        let mut entities = Vec::with_capacity(saved.len());
+        for entity in order {
+            let id = ids.len() as u64;
+            ids.insert(entity, id);
+
+            let camera = match world.get::<CameraComponent>(entity) {
+                Ok(camera) => Some(SceneCamera::from_component(&camera).ok_or_else(|| {
+                    anyhow!("The projection of the camera {:?} can't be saved", entity)
+                })?),
+                Err(_) => None,
+            };
+            let mesh = world.get::<MeshAssetComponent>(entity).ok();
+            if mesh.is_none() && world.get::<MeshComponent>(entity).is_ok() {
+                bail!(
+                    "The mesh of {:?} can't be saved without a MeshAssetComponent",
+                    entity
+                );
+            }
+            let mut components = BTreeMap::new();
+            for component in &registry.components {
+                if let Some(value) = (component.save)(world, entity) {
+                    let value = value.with_context(|| {
+                        format!(
+                            "Failed to save component {} of {:?}",
+                            component.name, entity
+                        )
+                    })?;
+                    components.insert(component.name.to_owned(), value);
+                }
+            }
+            entities.push(SceneEntity {
+                id,
+                parent: parent_of(entity).map(|parent| ids[&parent]),
+                transform: world.get::<TransformComponent>(entity).ok().map(|t| *t),
+                camera,
+                mesh: mesh.map(|mesh| (*mesh).clone()),
+                components,
+            });
+        }
+
+        Ok(Self {
+            version: SCENE_VERSION,
+            entities,
+        })
+    }
+
+    /// Spawns the entities of the scene, returned in the scene's order
+    ///
+    /// Nothing is left in the world if loading fails.
+    pub fn spawn(
+        &self, world: &mut hecs::World, registry: &SceneRegistry,
+    ) -> anyhow::Result<Vec<hecs::Entity>> {
+        if self.version > SCENE_VERSION {
+            bail!(
+                "Scene version {} is newer than the supported {}",
+                self.version,
+                SCENE_VERSION
+            );
+        }
+        let mut spawned = Vec::with_capacity(self.entities.len());
+        let result = self.spawn_into(world, registry, &mut spawned);
+        if result.is_err() {
+            for &entity in &spawned {
+                let _ = world.despawn(entity);
+            }
+        }
+        result.map(|_| spawned)
+    }
+
+    fn spawn_into(
+        &self, world: &mut hecs::World, registry: &SceneRegistry, spawned: &mut Vec<hecs::Entity>,
+    ) -> anyhow::Result<()> {
+        let mut entities = HashMap::with_capacity(self.entities.len());
+        for scene_entity in &self.entities {
+            let mut builder = hecs::EntityBuilder::new();
+            if let Some(transform) = scene_entity.transform {
+                builder.add(transform);
+            }
+            if let Some(camera) = &scene_entity.camera {
+                builder.add(camera.to_component());
+            }
+            if let Some(mesh) = &scene_entity.mesh {
+                builder.add(mesh.clone());
+            }
+            let entity = world.spawn(builder.build());
+            spawned.push(entity);
+            if entities.insert(scene_entity.id, entity).is_some() {
+                bail!("Scene entity id {} is used twice", scene_entity.id);
+            }
+
+            for (name, value) in &scene_entity.components {
+                let component = registry
+                    .components
+                    .iter()
+                    .find(|c| c.name == name)
+                    .ok_or_else(|| anyhow!("Unknown scene component {}", name))?;
+                (component.load)(world, entity, value.clone()).with_context(|| {
+                    format!(
+                        "Failed to load component {} of entity {}",
+                        name, scene_entity.id
+                    )
+                })?;
+            }
+        }
+
+        for scene_entity in &self.entities {
+            if let Some(parent) = scene_entity.parent {
+                let parent = *entities.get(&parent).ok_or_else(|| {
+                    anyhow!(
+                        "Parent {} of entity {} doesn't exist",
+                        parent,
+                        scene_entity.id
+                    )
+                })?;
+                world.add_child(parent, entities[&scene_entity.id])?;
+            }
+        }
+        Ok(())
+    }
+
+    pub fn to_string(&self, format: SceneFormat) -> anyhow::Result<String> {
+        Ok(match format {
+            SceneFormat::Ron => ron::ser::to_string_pretty(self, Default::default())?,
+            SceneFormat::Json => serde_json::to_string_pretty(self)?,
+        })
+    }
+
+    pub fn from_str(source: &str, format: SceneFormat) -> anyhow::Result<Self> {
+        Ok(match format {
+            SceneFormat::Ron => ron::from_str(source)?,
+            SceneFormat::Json => serde_json::from_str(source)?,
+        })
+    }
+}
+
+pub fn save_scene(
+    world: &hecs::World, registry: &SceneRegistry, format: SceneFormat,
+) -> anyhow::Result<String> {
+    Scene::from_world(world, registry)?.to_string(format)
+}
+
+pub fn load_scene(
+    world: &mut hecs::World, registry: &SceneRegistry, source: &str, format: SceneFormat,
+) -> anyhow::Result<Vec<hecs::Entity>> {
+    Scene::from_str(source, format)?.spawn(world, registry)
+}
+
+fn format_of(path: &Path) -> anyhow::Result<SceneFormat> {
+    SceneFormat::from_path(path)
+        .ok_or_else(|| anyhow!("{} is neither a .ron nor a .json file", path.display()))
+}
+
+/// Saves in the format given by the extension of the file
+pub fn save_scene_file(
+    world: &hecs::World, registry: &SceneRegistry, path: &Path,
+) -> anyhow::Result<()> {
+    let source = save_scene(world, registry, format_of(path)?)?;
+    fs::write(path, source).with_context(|| format!("Failed to write {}", path.display()))
+}
+
+/// Loads a file in the format given by its extension
+pub fn load_scene_file(
+    world: &mut hecs::World, registry: &SceneRegistry, path: &Path,
+) -> anyhow::Result<Vec<hecs::Entity>> {
+    let source =
+        fs::read_to_string(path).with_context(|| format!("Failed to read {}", path.display()))?;
+    load_scene(world, registry, &source, format_of(path)?)
+        .with_context(|| format!("Failed to load {}", path.display()))
+}
+
+/// Adds a [`MeshComponent`] to the entities that only have a [`MeshAssetComponent`]
+pub fn resolve_mesh_assets(
+    world: &mut hecs::World,
+    mut resolve: impl FnMut(&MeshAssetComponent) -> anyhow::Result<MeshComponent>,
+) -> anyhow::Result<()> {
+    let unresolved = world
+        .query::<&MeshAssetComponent>()
+        .without::<MeshComponent>()
+        .iter()
+        .map(|(entity, asset)| Ok((entity, resolve(asset)?)))
+        .collect::<anyhow::Result<Vec<_>>>()?;
+    for (entity, mesh) in unresolved {
+        world.insert_one(entity, mesh)?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use nalgebra::{UnitQuaternion, Vector3};
+
+    use super::*;
+    use crate::renderer::MeshRef;
+
+    #[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+    enum Behaviour {
+        Spin { speed: f32 },
+        Idle,
+    }
+    impl SceneComponent for Behaviour {
+        const NAME: &'static str = "behaviour";
+    }
+
+    fn transform(x: f32) -> TransformComponent {
+        TransformComponent {
+            scale: Vector3::new(1., 2., 0.5),
+            position: Vector3::new(x, 0.25, -3.),
+            rotation: UnitQuaternion::from_euler_angles(0.1, x, 0.3),
+        }
+    }
+
+    #[test]
+    fn round_trip() {
+        let mut registry = SceneRegistry::new();
+        registry.register::<Behaviour>();
+
+        let mut world = hecs::World::new();
+        let root = world.spawn((transform(1.), Behaviour::Spin { speed: 0.1 }));
+        let a = world.spawn((transform(2.), MeshAssetComponent {
+            mesh: "meshes/cube.obj".into(),
+            material_overrides: vec![None, Some("materials/red.ron".into())],
+        }));
+        let b = world.spawn((transform(3.), CameraComponent {
+            clear_color: Some(wgpu::Color::RED),
+            matrix: Box::new(PerspectiveCameraMatrix::reversed_infinite(1.5, 1., 0.1)),
+            is_enabled: false,
+        }));
+        let c = world.spawn((Behaviour::Idle,));
+        world.add_child(root, a).unwrap();
+        world.add_child(root, b).unwrap();
+        world.add_child(a, c).unwrap();
+        // Not part of the scene
+        world.spawn((5u32,));
+
+        for &format in &[SceneFormat::Ron, SceneFormat::Json] {
+            let source = save_scene(&world, &registry, format).unwrap();
+            let mut loaded = hecs::World::new();
+            // Offsets the new entity ids from the saved ones
+            loaded.spawn((TransformComponent::default(),));
+            let entities = load_scene(&mut loaded, &registry, &source, format).unwrap();
+            assert_eq!(entities.len(), 4);
+            assert_eq!(loaded.len(), 5);
+            let [l_root, l_a, l_c, l_b] = [entities[0], entities[1], entities[2], entities[3]];
+
+            assert_eq!(
+                loaded.get::<ParentComponent>(l_root).ok().and_then(|p| p.0),
+                None
+            );
+            assert_eq!(
+                loaded
+                    .get::<ChildrenComponent>(l_root)
+                    .unwrap()
+                    .0
+                    .as_slice(),
+                &[l_a, l_b]
+            );
+            assert_eq!(loaded.get::<ParentComponent>(l_c).unwrap().0, Some(l_a));
+
+            for &(saved, loaded_entity) in &[(root, l_root), (a, l_a), (b, l_b)] {
+                assert_eq!(
+                    *loaded.get::<TransformComponent>(loaded_entity).unwrap(),
+                    *world.get::<TransformComponent>(saved).unwrap()
+                );
+            }
+            assert_eq!(*loaded.get::<Behaviour>(l_root).unwrap(), Behaviour::Spin {
+                speed: 0.1
+            });
+            assert_eq!(*loaded.get::<Behaviour>(l_c).unwrap(), Behaviour::Idle);
+            assert_eq!(
+                *loaded.get::<MeshAssetComponent>(l_a).unwrap(),
+                *world.get::<MeshAssetComponent>(a).unwrap()
+            );
+
+            let camera = loaded.get::<CameraComponent>(l_b).unwrap();
+            assert!(!camera.is_enabled);
+            assert_eq!(camera.clear_color, Some(wgpu::Color::RED));
+            let matrix = camera
+                .matrix
+                .downcast_ref::<PerspectiveCameraMatrix>()
+                .unwrap();
+            assert_eq!(matrix.1, PerspectiveDepth::ReversedInfinite);
+            assert_eq!(matrix.0.aspect(), 1.5);
+        }
+    }
+
+    #[test]
+    fn inconsistent_hierarchies() {
+        let registry = SceneRegistry::new();
+        let mut world = hecs::World::new();
+        let parent = world.spawn((TransformComponent::default(),));
+        let child = world.spawn((TransformComponent::default(),));
+        world.add_child(parent, child).unwrap();
+        // Only the parent link is kept
+        world
+            .get_mut::<ChildrenComponent>(parent)
+            .unwrap()
+            .0
+            .clear();
+
+        let scene = Scene::from_world(&world, &registry).unwrap();
+        assert_eq!(scene.entities.len(), 2);
+        assert_eq!(scene.entities[1].parent, Some(scene.entities[0].id));
+
+        world
+            .insert_one(parent, ParentComponent(Some(child)))
+            .unwrap();
+        assert!(Scene::from_world(&world, &registry).is_err());
+
+        // Meshes can only be saved from their asset
+        let mut world = hecs::World::new();
+        world.spawn((MeshComponent::new(MeshRef(0)),));
+        assert!(Scene::from_world(&world, &registry).is_err());
+    }
+
+    #[test]
+    fn load_errors() {
+        let registry = SceneRegistry::new();
+        let mut world = hecs::World::new();
+        let load = |world: &mut hecs::World, source: &str| {
+            load_scene(world, &registry, source, SceneFormat::Ron)
+        };
+
+        assert!(load(&mut world, "(version: 2, entities: [])").is_err());
+        assert!(load(
+            &mut world,
+            "(version: 1, entities: [(id: 0, parent: Some(1))])"
+        )
+        .is_err());
+        assert!(load(
+            &mut world,
+            "(version: 1, entities: [(id: 0, components: {\"behaviour\": ()})])"
+        )
+        .is_err());
+        assert!(load(
+            &mut world,
+            "(version: 1, entities: [(id: 0, parent: Some(1)), (id: 1, parent: Some(0))])"
+        )
+        .is_err());
+        // Failed loads don't leave entities behind
+        assert_eq!(world.len(), 0);
+
+        assert_eq!(
+            load(
+                &mut world,
+                "(version: 1, entities: [(id: 0), (id: 1, parent: Some(0))])"
+            )
+            .unwrap()
+            .len(),
+            2
+        );
+    }
+}
@@ -0,0 +1,94 @@
+use std::time::Duration;
+
+/// Accumulates frame times into fixed simulation ticks so the simulation doesn't depend on the
+/// frame rate
+///
+/// Each frame, [`Self::advance`] gives the number of ticks to simulate, and rendering
+/// interpolates the last two ticks by [`Self::alpha`].
+#[derive(Debug, Clone)]
+pub struct FixedTimestep {
+    step: Duration,
+    /// Ticks simulated by a single frame at most, the time left is dropped so a slow simulation
+    /// can't fall further behind
+    pub max_ticks_per_frame: u32,
+    accumulator: Duration,
+    ticks: u64,
+}
+impl FixedTimestep {
+    pub fn new(step: Duration) -> Self {
+        assert!(step > Duration::ZERO, "The timestep can't be zero");
+        Self {
+            step,
+            max_ticks_per_frame: 8,
+            accumulator: Duration::ZERO,
+            ticks: 0,
+        }
+    }
+    pub fn from_rate(ticks_per_second: u32) -> Self {
+        assert!(ticks_per_second > 0, "The tick rate can't be zero");
+        Self::new(Duration::from_secs(1) / ticks_per_second)
+    }
+
+    /// Adds the time of a frame and returns the number of ticks to simulate
+    pub fn advance(&mut self, frame_time: Duration) -> u32 {
+        self.accumulator += frame_time;
+        let mut ticks = 0;
+        while self.accumulator >= self.step {
+            if ticks == self.max_ticks_per_frame {
+                self.accumulator = Duration::ZERO;
+                break;
+            }
+            self.accumulator -= self.step;
+            ticks += 1;
+        }
+        self.ticks += ticks as u64;
+        ticks
+    }
+
+    pub fn step(&self) -> Duration { self.step }
+    pub fn step_secs(&self) -> f32 { self.step.as_secs_f32() }
+    /// Number of ticks simulated so far
+    pub fn ticks(&self) -> u64 { self.ticks }
+    /// Simulated time, a multiple of the step
+    pub fn elapsed(&self) -> Duration {
+        let nanos = self.step.as_nanos() * self.ticks as u128;
+        Duration::new(
+            (nanos / 1_000_000_000) as u64,
+            (nanos % 1_000_000_000) as u32,
+        )
+    }
+    /// Progress from the previous tick to the current one of the time left in the accumulator,
+    /// between 0 and 1
+    pub fn alpha(&self) -> f32 { self.accumulator.as_secs_f32() / self.step.as_secs_f32() }
+}
+
+#[cfg(test)]
+mod tests {
+    use approx::assert_relative_eq;
+
+    use super::*;
+
+    #[test]
+    fn fixed_timestep() {
+        let mut timestep = FixedTimestep::new(Duration::from_millis(10));
+        assert_eq!(timestep.advance(Duration::from_millis(25)), 2);
+        assert_relative_eq!(timestep.alpha(), 0.5, epsilon = 1e-4);
+        assert_eq!(timestep.advance(Duration::from_millis(4)), 0);
+        assert_eq!(timestep.advance(Duration::from_millis(1)), 1);
+        assert_relative_eq!(timestep.alpha(), 0., epsilon = 1e-4);
+        assert_eq!(timestep.ticks(), 3);
+        assert_eq!(timestep.elapsed(), Duration::from_millis(30));
+
+        // Long frames are clamped
+        assert_eq!(timestep.advance(Duration::from_secs(1)), 8);
+        assert_relative_eq!(timestep.alpha(), 0.);
+
+        // Tick counts past u32::MAX
+        timestep.ticks = 1 << 33;
+        assert_eq!(timestep.elapsed(), Duration::from_millis(10 << 33));
+    }
+
+    #[test]
+    #[should_panic(expected = "The tick rate can't be zero")]
+    fn zero_tick_rate() { FixedTimestep::from_rate(0); }
+}
@@ -1,26 +1,46 @@
-use std::f32;
+use std::{collections::HashSet, f32};
 
 use approx::*;
-use nalgebra::{base::dimension::Dim, Matrix4, Translation3, UnitQuaternion, Vector3, Vector4};
+use nalgebra::{
+    base::dimension::Dim,
+    Affine3,
+    Matrix3,
+    Matrix4,
+    Point3,
+    Translation3,
+    Unit,
+    UnitQuaternion,
+    Vector3,
+    Vector4,
+};
+use rayon::prelude::*;
+use serde::{Deserialize, Serialize};
 
 use crate::hecs_extension::*;
 
-#[derive(Debug, Copy, Clone, PartialEq)]
+#[derive(Debug, Copy, Clone, PartialEq, Serialize, Deserialize)]
 pub struct TransformComponent {
     pub scale: Vector3<f32>,
     pub position: Vector3<f32>,
     pub rotation: UnitQuaternion<f32>,
 }
 impl TransformComponent {
+    /// Decomposes a matrix without shear, a negative determinant is represented by a negative
+    /// scale on X
     pub fn from_homogeneous(matrix: &Matrix4<f32>) -> Self {
+        let mut scale = Vector3::from_iterator(matrix.column_iter().map(|a| a.xyz().norm()));
+        let mut linear: Matrix3<f32> =
+            matrix.resize_generic(Dim::from_usize(3), Dim::from_usize(3), 1.);
+        if linear.determinant() < 0. {
+            scale.x = -scale.x;
+        }
+        for (mut column, scale) in linear.column_iter_mut().zip(scale.iter()) {
+            column /= *scale;
+        }
         Self {
-            scale: Vector3::from_iterator(matrix.column_iter().map(|a| a.xyz().norm())),
+            scale,
             position: matrix.column(3).xyz(),
-            rotation: UnitQuaternion::from_matrix(&matrix.resize_generic(
-                Dim::from_usize(3),
-                Dim::from_usize(3),
-                1.,
-            )),
+            rotation: UnitQuaternion::from_matrix(&linear),
         }
     }
 
@@ -29,6 +49,83 @@ impl TransformComponent {
             * self.rotation.to_homogeneous()
             * Matrix4::from_diagonal(&Vector4::new(self.scale.x, self.scale.y, self.scale.z, 1.))
     }
+    pub fn to_affine(&self) -> Affine3<f32> {
+        Affine3::from_matrix_unchecked(self.to_homogeneous())
+    }
+    /// Transformation from the parent space to the local space, which is only a TRS transform
+    /// without non-uniform scale
+    pub fn inverse(&self) -> Affine3<f32> {
+        Affine3::from_matrix_unchecked(
+            Matrix4::from_diagonal(&Vector4::new(
+                1. / self.scale.x,
+                1. / self.scale.y,
+                1. / self.scale.z,
+                1.,
+            )) * self.rotation.inverse().to_homogeneous()
+                * Translation3::from(-self.position).to_homogeneous(),
+        )
+    }
+
+    /// Linear interpolation of the position and scale and spherical one of the rotation
+    pub fn interpolate(&self, other: &Self, alpha: f32) -> Self {
+        Self {
+            scale: self.scale.lerp(&other.scale, alpha),
+            position: self.position.lerp(&other.position, alpha),
+            // Half turns have no single shortest path
+            rotation: self
+                .rotation
+                .try_slerp(&other.rotation, alpha, f32::EPSILON)
+                .unwrap_or(if alpha < 0.5 {
+                    self.rotation
+                }
+                else {
+                    other.rotation
+                }),
+        }
+    }
+
+    /// Rotates the transform so its forward vector points towards the target
+    pub fn look_at(&mut self, target: &Point3<f32>, up: &Vector3<f32>) {
+        self.rotation = UnitQuaternion::face_towards(&(self.position - target.coords), up);
+    }
+    /// Local -Z axis, the direction cameras look towards
+    pub fn forward(&self) -> Unit<Vector3<f32>> { -(self.rotation * Vector3::z_axis()) }
+    /// Local X axis
+    pub fn right(&self) -> Unit<Vector3<f32>> { self.rotation * Vector3::x_axis() }
+    /// Local Y axis
+    pub fn up(&self) -> Unit<Vector3<f32>> { self.rotation * Vector3::y_axis() }
+
+    /// Moves along the rotated axes, the offset isn't scaled
+    pub fn translate_local(&mut self, offset: &Vector3<f32>) {
+        self.position += self.rotation * offset;
+    }
+    /// Applies a rotation around the local axes
+    pub fn rotate_local(&mut self, rotation: &UnitQuaternion<f32>) { self.rotation *= rotation; }
+    /// Applies a rotation around the parent's axes
+    pub fn rotate_world(&mut self, rotation: &UnitQuaternion<f32>) {
+        self.rotation = rotation * self.rotation;
+    }
+    /// Orbits around a point of the parent space, also rotating the transform
+    pub fn rotate_around(&mut self, point: &Point3<f32>, axis: &Unit<Vector3<f32>>, angle: f32) {
+        let rotation = UnitQuaternion::from_axis_angle(axis, angle);
+        self.position = point.coords + rotation * (self.position - point.coords);
+        self.rotate_world(&rotation);
+    }
+
+    /// Transforms a point from the local space to the parent space
+    pub fn transform_point(&self, point: &Point3<f32>) -> Point3<f32> {
+        Point3::from(self.transform_vector(&point.coords) + self.position)
+    }
+    /// Transforms a vector from the local space to the parent space, ignoring the translation
+    pub fn transform_vector(&self, vector: &Vector3<f32>) -> Vector3<f32> {
+        self.rotation * self.scale.component_mul(vector)
+    }
+    pub fn inverse_transform_point(&self, point: &Point3<f32>) -> Point3<f32> {
+        Point3::from(self.inverse_transform_vector(&(point.coords - self.position)))
+    }
+    pub fn inverse_transform_vector(&self, vector: &Vector3<f32>) -> Vector3<f32> {
+        (self.rotation.inverse() * vector).component_div(&self.scale)
+    }
 }
 impl Default for TransformComponent {
     fn default() -> Self {
@@ -64,29 +161,339 @@ impl RelativeEq for TransformComponent {
     }
 }
 
+/// Ancestors of the entity up to the first one without transform, closest first
+fn transformed_ancestors(
+    world: &hecs::World, entity: hecs::Entity,
+) -> anyhow::Result<Vec<hecs::Entity>> {
+    let mut ancestors = Vec::new();
+    for parent in world.ancestors(entity) {
+        if parent == entity || ancestors.contains(&parent) {
+            anyhow::bail!("The parents of {:?} form a cycle", entity);
+        }
+        if world.get::<TransformComponent>(parent).is_err() {
+            break;
+        }
+        ancestors.push(parent);
+    }
+    Ok(ancestors)
+}
+
+/// Walks the parent chain of the entity, prefer [`GlobalTransformComponent`] which is cached
 pub fn get_global_transform(
     world: &hecs::World, entity: hecs::Entity,
+) -> anyhow::Result<Affine3<f32>> {
+    let mut global_transform = world.get::<TransformComponent>(entity)?.to_affine();
+    for parent in transformed_ancestors(world, entity)? {
+        global_transform = world.get::<TransformComponent>(parent)?.to_affine() * global_transform;
+    }
+    Ok(global_transform)
+}
+
+/// Same as [`get_global_transform`] from the [`PreviousTransformComponent`]s, the current
+/// transform being used for entities without one
+fn get_previous_global_transform(
+    world: &hecs::World, entity: hecs::Entity,
+) -> anyhow::Result<Affine3<f32>> {
+    let previous_local = |entity| -> Result<Affine3<f32>, hecs::ComponentError> {
+        Ok(match world.get::<PreviousTransformComponent>(entity) {
+            Ok(previous) => previous.0.to_affine(),
+            Err(_) => world.get::<TransformComponent>(entity)?.to_affine(),
+        })
+    };
+    let mut global_transform = previous_local(entity)?;
+    for parent in transformed_ancestors(world, entity)? {
+        global_transform = previous_local(parent)? * global_transform;
+    }
+    Ok(global_transform)
+}
+
+/// Global transform of the entity's parent, identity for roots
+pub fn get_parent_global_transform(
+    world: &hecs::World, entity: hecs::Entity,
+) -> anyhow::Result<Affine3<f32>> {
+    let parent = world
+        .get::<ParentComponent>(entity)
+        .ok()
+        .and_then(|parent| parent.0)
+        .filter(|&parent| world.get::<TransformComponent>(parent).is_ok());
+    match parent {
+        Some(parent) => get_global_transform(world, parent),
+        None => Ok(Affine3::identity()),
+    }
+}
+/// Transformation from world space to the local space of the entity, the inverse of
+/// [`get_global_transform`]
+pub fn world_to_local(world: &hecs::World, entity: hecs::Entity) -> anyhow::Result<Affine3<f32>> {
+    get_global_transform(world, entity)?
+        .try_inverse()
+        .ok_or_else(|| anyhow::anyhow!("The entity's transform isn't invertible"))
+}
+/// Local transform that places the entity at the given world space transform under its current
+/// parent
+///
+/// Shear coming from the parents can't be represented and is lost.
+pub fn world_to_parent_space(
+    world: &hecs::World, entity: hecs::Entity, global: &Affine3<f32>,
 ) -> anyhow::Result<TransformComponent> {
-    let mut global_transform = *world.get::<TransformComponent>(entity)?;
-    let mut current_entity = entity;
-    while let Some(parent) = {
+    let parent = get_parent_global_transform(world, entity)?
+        .try_inverse()
+        .ok_or_else(|| anyhow::anyhow!("The parent's transform isn't invertible"))?;
+    Ok(TransformComponent::from_homogeneous(
+        (parent * global).matrix(),
+    ))
+}
+
+/// Reparents the entity, or makes it a root without parent, changing its local transform so it
+/// doesn't move in world space
+///
+/// The [`PreviousTransformComponent`] is converted too so that interpolation doesn't jump. Like
+/// [`world_to_parent_space`], shear coming from the parents can't be represented and is lost.
+pub fn set_parent_keep_world(
+    world: &mut hecs::World, entity: hecs::Entity, parent: Option<hecs::Entity>,
+) -> anyhow::Result<()> {
+    // Parents without transform are at the origin
+    let transformed_parent =
+        parent.filter(|&parent| world.get::<TransformComponent>(parent).is_ok());
+    let to_new_parent_space = |global: Affine3<f32>, parent_global: Affine3<f32>| {
+        let inverse = parent_global
+            .try_inverse()
+            .ok_or_else(|| anyhow::anyhow!("The new parent's transform isn't invertible"))?;
+        Ok::<_, anyhow::Error>(TransformComponent::from_homogeneous(
+            (inverse * global).matrix(),
+        ))
+    };
+    let local = to_new_parent_space(
+        get_global_transform(world, entity)?,
+        match transformed_parent {
+            Some(parent) => get_global_transform(world, parent)?,
+            None => Affine3::identity(),
+        },
+    )?;
+    let previous_local = match world.get::<PreviousTransformComponent>(entity) {
+        Ok(_) => Some(to_new_parent_space(
+            get_previous_global_transform(world, entity)?,
+            match transformed_parent {
+                Some(parent) => get_previous_global_transform(world, parent)?,
+                None => Affine3::identity(),
+            },
+        )?),
+        Err(_) => None,
+    };
+
+    match parent {
+        Some(parent) => world.add_child(parent, entity)?,
+        None => world.remove_parent(entity)?,
+    }
+    *world.get_mut::<TransformComponent>(entity)? = local;
+    if let Some(previous_local) = previous_local {
+        world.get_mut::<PreviousTransformComponent>(entity)?.0 = previous_local;
+    }
+    Ok(())
+}
+
+/// World space transform of an entity, kept up to date by [`update_global_transforms`]
+#[derive(Debug, Clone, PartialEq)]
+pub struct GlobalTransformComponent {
+    /// Parent's global transform times the local one, which can have shear when a parent has a
+    /// non-uniform scale
+    affine: Affine3<f32>,
+    /// Same from the [`PreviousTransformComponent`]s of the hierarchy
+    previous: Affine3<f32>,
+    /// Local transforms and parent the matrices were computed from, to detect changes
+    local: Option<(TransformComponent, TransformComponent)>,
+    parent: Option<hecs::Entity>,
+}
+impl GlobalTransformComponent {
+    pub fn affine(&self) -> &Affine3<f32> { &self.affine }
+    pub fn matrix(&self) -> &Matrix4<f32> { self.affine.matrix() }
+    /// Global transform at the previous simulation tick, see [`snapshot_transforms`]
+    pub fn previous(&self) -> &Affine3<f32> { &self.previous }
+    /// Global transform between the previous and the current tick, see
+    /// [`FixedTimestep::alpha`](crate::time::FixedTimestep::alpha)
+    ///
+    /// Both transforms are decomposed to be interpolated which loses the shear of moving
+    /// entities.
+    pub fn interpolated(&self, alpha: f32) -> Matrix4<f32> {
+        if self.previous == self.affine || alpha >= 1. {
+            return *self.affine.matrix();
+        }
+        TransformComponent::from_homogeneous(self.previous.matrix())
+            .interpolate(
+                &TransformComponent::from_homogeneous(self.affine.matrix()),
+                alpha,
+            )
+            .to_homogeneous()
+    }
+}
+impl Default for GlobalTransformComponent {
+    fn default() -> Self {
+        Self {
+            affine: Affine3::identity(),
+            previous: Affine3::identity(),
+            local: None,
+            parent: None,
+        }
+    }
+}
+
+/// Local transform of an entity at the previous simulation tick, rendering interpolates from it
+/// to the [`TransformComponent`]
+#[derive(Debug, Clone, Copy)]
+pub struct PreviousTransformComponent(pub TransformComponent);
+
+/// Saves the transforms of every entity into their [`PreviousTransformComponent`], to be called
+/// before each simulation tick
+pub fn snapshot_transforms(world: &mut hecs::World) {
+    let missing = world
+        .query::<&TransformComponent>()
+        .without::<PreviousTransformComponent>()
+        .iter()
+        .map(|(entity, transform)| (entity, *transform))
+        .collect::<Vec<_>>();
+    for (entity, transform) in missing {
+        world
+            .insert_one(entity, PreviousTransformComponent(transform))
+            .unwrap();
+    }
+    for (_, (transform, previous)) in
+        world.query_mut::<(&TransformComponent, &mut PreviousTransformComponent)>()
+    {
+        previous.0 = *transform;
+    }
+}
+
+/// Updates the [`GlobalTransformComponent`] of every entity with a [`TransformComponent`],
+/// adding the ones that are missing
+///
+/// Hierarchies are walked from their roots in parallel, only the entities whose local transform
+/// or parent changed are recomputed along with their descendants.
+///
+/// Returns the entities that can't be reached from a root, because their parents form a cycle or
+/// don't list them as children, their global transform is left as is. See [`repair_hierarchy`].
+pub fn update_global_transforms(world: &mut hecs::World) -> Vec<hecs::Entity> {
+    let missing = world
+        .query::<()>()
+        .with::<TransformComponent>()
+        .without::<GlobalTransformComponent>()
+        .iter()
+        .map(|(entity, _)| entity)
+        .collect::<Vec<_>>();
+    for entity in missing {
         world
-            .get::<ParentComponent>(current_entity)
-            .ok()
-            .map(|a| a.0)
-            .flatten()
-    } {
-        match world.get::<TransformComponent>(parent) {
-            Ok(t) => {
-                global_transform = TransformComponent::from_homogeneous(
-                    &(global_transform.to_homogeneous() * t.to_homogeneous()),
+            .insert_one(entity, GlobalTransformComponent::default())
+            .unwrap();
+    }
+
+    let (updates, unreachable) = {
+        let world = &*world;
+        // Entities whose parent doesn't have a transform are roots too
+        let roots = world
+            .query::<Option<&ParentComponent>>()
+            .with::<TransformComponent>()
+            .iter()
+            .filter(|(_, parent)| match parent.and_then(|parent| parent.0) {
+                Some(parent) => world.get::<TransformComponent>(parent).is_err(),
+                None => true,
+            })
+            .map(|(entity, _)| entity)
+            .collect::<Vec<_>>();
+        let walks = roots
+            .par_iter()
+            .map(|&root| {
+                let mut updates = Vec::new();
+                let mut visited = Vec::new();
+                collect_global_transform_updates(
+                    world,
+                    root,
+                    None,
+                    (&Affine3::identity(), &Affine3::identity()),
+                    false,
+                    &mut updates,
+                    &mut visited,
                 );
-                current_entity = parent;
-            }
-            _ => break,
+                (updates, visited)
+            })
+            .collect::<Vec<_>>();
+        let visited = walks
+            .iter()
+            .flat_map(|(_, visited)| visited.iter().copied())
+            .collect::<HashSet<_>>();
+        let unreachable = world
+            .query::<()>()
+            .with::<TransformComponent>()
+            .iter()
+            .map(|(entity, _)| entity)
+            .filter(|entity| !visited.contains(entity))
+            .collect::<Vec<_>>();
+        let updates = walks
+            .into_iter()
+            .flat_map(|(updates, _)| updates)
+            .collect::<Vec<_>>();
+        (updates, unreachable)
+    };
+    for (entity, global) in updates {
+        *world.get_mut::<GlobalTransformComponent>(entity).unwrap() = global;
+    }
+    unreachable
+}
+
+fn collect_global_transform_updates(
+    world: &hecs::World, entity: hecs::Entity, parent: Option<hecs::Entity>,
+    (parent_affine, parent_previous): (&Affine3<f32>, &Affine3<f32>), parent_changed: bool,
+    updates: &mut Vec<(hecs::Entity, GlobalTransformComponent)>, visited: &mut Vec<hecs::Entity>,
+) {
+    let (affine, previous, changed) = {
+        let (local, global) = match (
+            world.get::<TransformComponent>(entity),
+            world.get::<GlobalTransformComponent>(entity),
+        ) {
+            (Ok(local), Ok(global)) => (*local, global),
+            _ => return,
+        };
+        visited.push(entity);
+        let previous_local = world
+            .get::<PreviousTransformComponent>(entity)
+            .map_or(local, |previous| previous.0);
+        if parent_changed
+            || global.local != Some((local, previous_local))
+            || global.parent != parent
+        {
+            let affine = parent_affine * local.to_affine();
+            let previous = parent_previous * previous_local.to_affine();
+            updates.push((entity, GlobalTransformComponent {
+                affine,
+                previous,
+                local: Some((local, previous_local)),
+                parent,
+            }));
+            (affine, previous, true)
+        }
+        else {
+            (global.affine, global.previous, false)
+        }
+    };
+    if let Ok(children) = world.get::<ChildrenComponent>(entity) {
+        // Children that don't link back are skipped, which keeps inconsistent hierarchies from
+        // being walked in cycles
+        let is_child = |child| {
+            world
+                .get::<ParentComponent>(child)
+                .ok()
+                .map(|parent| parent.0)
+                == Some(Some(entity))
+        };
+        for &child in children.0.iter().filter(|&&child| is_child(child)) {
+            collect_global_transform_updates(
+                world,
+                child,
+                Some(entity),
+                (&affine, &previous),
+                changed,
+                updates,
+                visited,
+            );
         }
     }
-    Ok(global_transform)
 }
 
 #[cfg(test)]
@@ -106,5 +513,365 @@ mod tests {
         let other_transform = TransformComponent::from_homogeneous(&matrix);
 
         assert_relative_eq!(transform, other_transform);
+
+        let mirrored = TransformComponent {
+            scale: Vector3::new(-2., 1., 3.),
+            ..transform
+        };
+        let other_mirrored = TransformComponent::from_homogeneous(&mirrored.to_homogeneous());
+        assert_relative_eq!(mirrored, other_mirrored, epsilon = 1e-4);
+    }
+
+    fn spawn_chain(world: &mut hecs::World, transforms: &[TransformComponent]) -> hecs::Entity {
+        let mut parent = None;
+        for transform in transforms {
+            let entity = world.spawn((
+                *transform,
+                ParentComponent(None),
+                ChildrenComponent(Default::default()),
+            ));
+            if let Some(parent) = parent {
+                world.add_child(parent, entity).unwrap();
+            }
+            parent = Some(entity);
+        }
+        parent.unwrap()
+    }
+    fn assert_global_matrix(transforms: &[TransformComponent], expected: &Matrix4<f32>) {
+        let mut world = hecs::World::new();
+        let leaf = spawn_chain(&mut world, transforms);
+        update_global_transforms(&mut world);
+        let cached = *world
+            .get::<GlobalTransformComponent>(leaf)
+            .unwrap()
+            .matrix();
+        assert_relative_eq!(cached, expected, epsilon = 1e-4);
+        let walked = get_global_transform(&world, leaf).unwrap();
+        assert_relative_eq!(*walked.matrix(), expected, epsilon = 1e-4);
+    }
+
+    #[test]
+    fn hierarchy_composition() {
+        // The child is placed in the rotated frame of its parent
+        let rotated_parent = TransformComponent {
+            position: Vector3::new(0., 0., 5.),
+            rotation: UnitQuaternion::from_euler_angles(0., 0., f32::consts::FRAC_PI_2),
+            ..Default::default()
+        };
+        let child = TransformComponent {
+            position: Vector3::new(1., 0., 0.),
+            ..Default::default()
+        };
+        assert_global_matrix(
+            &[rotated_parent, child],
+            &(Translation3::new(0., 1., 5.).to_homogeneous()
+                * rotated_parent.rotation.to_homogeneous()),
+        );
+
+        // A rotated child under a non-uniform scale gets sheared
+        let scaled_parent = TransformComponent {
+            scale: Vector3::new(2., 1., 1.),
+            ..Default::default()
+        };
+        let rotated_child = TransformComponent {
+            rotation: UnitQuaternion::from_euler_angles(0., 0., f32::consts::FRAC_PI_4),
+            ..Default::default()
+        };
+        let expected = scaled_parent.to_homogeneous() * rotated_child.to_homogeneous();
+        assert!(
+            expected
+                .column(0)
+                .xyz()
+                .dot(&expected.column(1).xyz())
+                .abs()
+                > 0.1
+        );
+        assert_global_matrix(&[scaled_parent, rotated_child], &expected);
+
+        // Deep hierarchy, walking around a square
+        let step = TransformComponent {
+            position: Vector3::new(1., 0., 0.),
+            rotation: UnitQuaternion::from_euler_angles(0., 0., f32::consts::FRAC_PI_2),
+            scale: Vector3::new(1., 1., -1.),
+        };
+        let chain = vec![step; 10];
+        let expected = chain
+            .iter()
+            .fold(Matrix4::identity(), |m, t| m * t.to_homogeneous());
+        assert_global_matrix(&chain, &expected);
+        assert_relative_eq!(
+            expected.column(3).xyz(),
+            Vector3::new(1., 1., 0.),
+            epsilon = 1e-4
+        );
+    }
+
+    #[test]
+    fn global_transform_propagation() {
+        let mut world = hecs::World::new();
+        let translation = |x: f32| TransformComponent {
+            position: Vector3::new(x, 0., 0.),
+            ..Default::default()
+        };
+        let spawn = |world: &mut hecs::World, x| {
+            world.spawn((
+                translation(x),
+                ParentComponent(None),
+                ChildrenComponent(Default::default()),
+            ))
+        };
+        let root = spawn(&mut world, 1.);
+        let child = spawn(&mut world, 2.);
+        let grandchild = spawn(&mut world, 4.);
+        let other = spawn(&mut world, 8.);
+        world.add_child(root, child).unwrap();
+        world.add_child(child, grandchild).unwrap();
+
+        let global_x = |world: &hecs::World, entity| {
+            world
+                .get::<GlobalTransformComponent>(entity)
+                .unwrap()
+                .matrix()[(0, 3)]
+        };
+        update_global_transforms(&mut world);
+        assert_relative_eq!(global_x(&world, grandchild), 7.);
+        assert_relative_eq!(global_x(&world, other), 8.);
+
+        // Unchanged entities are not recomputed
+        world
+            .get_mut::<GlobalTransformComponent>(other)
+            .unwrap()
+            .affine = Affine3::from_matrix_unchecked(Matrix4::zeros());
+        world
+            .get_mut::<TransformComponent>(root)
+            .unwrap()
+            .position
+            .x = 16.;
+        update_global_transforms(&mut world);
+        assert_relative_eq!(global_x(&world, grandchild), 22.);
+        assert_relative_eq!(global_x(&world, other), 0.);
+
+        world.remove_parent(child).unwrap();
+        update_global_transforms(&mut world);
+        assert_relative_eq!(global_x(&world, child), 2.);
+        assert_relative_eq!(global_x(&world, grandchild), 6.);
+
+        // A cycle in the children lists isn't followed
+        world
+            .get_mut::<ChildrenComponent>(grandchild)
+            .unwrap()
+            .0
+            .push(child);
+        world
+            .get_mut::<TransformComponent>(child)
+            .unwrap()
+            .position
+            .x = 3.;
+        assert!(update_global_transforms(&mut world).is_empty());
+        assert_relative_eq!(global_x(&world, grandchild), 7.);
+
+        // Entities whose parents form a cycle can't be reached from a root and are reported
+        let first = spawn(&mut world, 1.);
+        let second = spawn(&mut world, 2.);
+        world.get_mut::<ParentComponent>(first).unwrap().0 = Some(second);
+        world.get_mut::<ParentComponent>(second).unwrap().0 = Some(first);
+        world
+            .get_mut::<ChildrenComponent>(first)
+            .unwrap()
+            .0
+            .push(second);
+        world
+            .get_mut::<ChildrenComponent>(second)
+            .unwrap()
+            .0
+            .push(first);
+        let mut unreachable = update_global_transforms(&mut world);
+        unreachable.sort();
+        assert_eq!(unreachable, vec![first.min(second), first.max(second)]);
+        assert_relative_eq!(global_x(&world, first), 0.);
+
+        repair_hierarchy(&mut world);
+        assert!(update_global_transforms(&mut world).is_empty());
+        assert_relative_eq!(global_x(&world, first), 1.);
+        assert_relative_eq!(global_x(&world, second), 3.);
+    }
+
+    #[test]
+    fn transform_helpers() {
+        let mut transform = TransformComponent {
+            position: Vector3::new(1., 2., 3.),
+            scale: Vector3::new(2., 3., 4.),
+            ..Default::default()
+        };
+        transform.look_at(&Point3::new(1., 2., -7.), &Vector3::y());
+        assert_relative_eq!(*transform.forward(), -Vector3::z(), epsilon = 1e-6);
+        transform.look_at(&Point3::new(11., 2., 3.), &Vector3::y());
+        assert_relative_eq!(*transform.forward(), Vector3::x(), epsilon = 1e-6);
+        assert_relative_eq!(*transform.up(), Vector3::y(), epsilon = 1e-6);
+        assert_relative_eq!(*transform.right(), Vector3::z(), epsilon = 1e-6);
+
+        transform.translate_local(&Vector3::new(0., 0., -1.));
+        assert_relative_eq!(transform.position, Vector3::new(2., 2., 3.), epsilon = 1e-6);
+
+        let point = Point3::new(0.5, -1., 2.);
+        let transformed = transform.transform_point(&point);
+        assert_relative_eq!(transformed, transform.to_affine() * point, epsilon = 1e-5);
+        assert_relative_eq!(
+            transform.inverse_transform_point(&transformed),
+            point,
+            epsilon = 1e-5
+        );
+        assert_relative_eq!(transform.inverse() * transformed, point, epsilon = 1e-5);
+
+        let yaw = UnitQuaternion::from_axis_angle(&Vector3::y_axis(), 0.3);
+        let pitch = UnitQuaternion::from_axis_angle(&Vector3::x_axis(), 0.2);
+        let mut local = transform;
+        local.rotate_local(&pitch);
+        assert_relative_eq!(local.rotation, transform.rotation * pitch);
+        let mut world_rotated = transform;
+        world_rotated.rotate_world(&yaw);
+        assert_relative_eq!(world_rotated.rotation, yaw * transform.rotation);
+
+        let mut orbiting = TransformComponent {
+            position: Vector3::new(2., 0., 0.),
+            ..Default::default()
+        };
+        orbiting.rotate_around(
+            &Point3::new(1., 0., 0.),
+            &Vector3::z_axis(),
+            f32::consts::PI,
+        );
+        assert_relative_eq!(orbiting.position, Vector3::zeros(), epsilon = 1e-6);
+        assert_relative_eq!(*orbiting.right(), -Vector3::x(), epsilon = 1e-6);
+    }
+
+    #[test]
+    fn world_local_conversions() {
+        let mut world = hecs::World::new();
+        let parent = TransformComponent {
+            position: Vector3::new(0., 10., 0.),
+            rotation: UnitQuaternion::from_euler_angles(0., 0., f32::consts::FRAC_PI_2),
+            scale: Vector3::new(2., 2., 2.),
+        };
+        let child = spawn_chain(&mut world, &[parent, TransformComponent::default()]);
+
+        let to_local = world_to_local(&world, child).unwrap();
+        assert_relative_eq!(
+            to_local * Point3::new(0., 12., 0.),
+            Point3::new(1., 0., 0.),
+            epsilon = 1e-5
+        );
+        let parent_global = get_parent_global_transform(&world, child).unwrap();
+        assert_relative_eq!(
+            *parent_global.matrix(),
+            parent.to_homogeneous(),
+            epsilon = 1e-5
+        );
+
+        let target = TransformComponent {
+            position: Vector3::new(4., 10., 0.),
+            ..Default::default()
+        };
+        let local = world_to_parent_space(&world, child, &target.to_affine()).unwrap();
+        assert_relative_eq!(local.position, Vector3::new(0., -2., 0.), epsilon = 1e-5);
+        assert_relative_eq!(local.scale, Vector3::new(0.5, 0.5, 0.5), epsilon = 1e-5);
+        *world.get_mut::<TransformComponent>(child).unwrap() = local;
+        let global = get_global_transform(&world, child).unwrap();
+        assert_relative_eq!(*global.matrix(), target.to_homogeneous(), epsilon = 1e-5);
+    }
+
+    #[test]
+    fn interpolated_global_transforms() {
+        let mut world = hecs::World::new();
+        let parent = spawn_chain(&mut world, &[TransformComponent::default()]);
+        let child = spawn_chain(&mut world, &[TransformComponent {
+            position: Vector3::new(1., 0., 0.),
+            ..Default::default()
+        }]);
+        world.add_child(parent, child).unwrap();
+
+        snapshot_transforms(&mut world);
+        {
+            let mut transform = world.get_mut::<TransformComponent>(parent).unwrap();
+            transform.position.y = 4.;
+            transform.rotation = UnitQuaternion::from_euler_angles(0., 0., f32::consts::FRAC_PI_2);
+        }
+        update_global_transforms(&mut world);
+
+        let global = world.get::<GlobalTransformComponent>(child).unwrap();
+        let position = |alpha| global.interpolated(alpha).column(3).xyz();
+        assert_relative_eq!(position(0.), Vector3::new(1., 0., 0.), epsilon = 1e-5);
+        assert_relative_eq!(position(1.), Vector3::new(0., 5., 0.), epsilon = 1e-5);
+        // The global transforms are interpolated, not the path around the parent
+        assert_relative_eq!(position(0.5), Vector3::new(0.5, 2.5, 0.), epsilon = 1e-5);
+        let rotation = UnitQuaternion::from_euler_angles(0., 0., f32::consts::FRAC_PI_4);
+        assert_relative_eq!(
+            TransformComponent::from_homogeneous(&global.interpolated(0.5)).rotation,
+            rotation,
+            epsilon = 1e-5
+        );
+    }
+
+    #[test]
+    fn reparenting_keeps_world_placement() {
+        let mut world = hecs::World::new();
+        let platform = spawn_chain(&mut world, &[TransformComponent {
+            position: Vector3::new(10., 0., 0.),
+            rotation: UnitQuaternion::from_euler_angles(0., f32::consts::FRAC_PI_2, 0.),
+            scale: Vector3::new(2., 2., 2.),
+        }]);
+        let other = spawn_chain(&mut world, &[TransformComponent {
+            position: Vector3::new(0., -5., 3.),
+            ..Default::default()
+        }]);
+        let object = spawn_chain(&mut world, &[TransformComponent {
+            position: Vector3::new(1., 2., 3.),
+            rotation: UnitQuaternion::from_euler_angles(0.3, 0., 0.),
+            ..Default::default()
+        }]);
+        let original = *world.get::<TransformComponent>(object).unwrap();
+
+        for &parent in &[Some(platform), Some(other), None] {
+            set_parent_keep_world(&mut world, object, parent).unwrap();
+            assert_eq!(world.get::<ParentComponent>(object).unwrap().0, parent);
+            let global = get_global_transform(&world, object).unwrap();
+            assert_relative_eq!(*global.matrix(), original.to_homogeneous(), epsilon = 1e-4);
+        }
+        let local = *world.get::<TransformComponent>(object).unwrap();
+        assert_relative_eq!(local, original, epsilon = 1e-4);
+
+        // Failed reparenting leaves the entity untouched
+        world.add_child(object, platform).unwrap();
+        assert!(set_parent_keep_world(&mut world, object, Some(platform)).is_err());
+        assert_eq!(world.get::<ParentComponent>(object).unwrap().0, None);
+
+        // Cycles in the parents are reported instead of walked forever
+        world.get_mut::<ParentComponent>(object).unwrap().0 = Some(platform);
+        assert!(get_global_transform(&world, platform).is_err());
+        assert!(world_to_local(&world, object).is_err());
+        assert!(set_parent_keep_world(&mut world, object, None).is_err());
+
+        // The interpolated placement doesn't jump when moving to a parent that moved too
+        let translation = |x: f32| TransformComponent {
+            position: Vector3::new(x, 0., 0.),
+            ..Default::default()
+        };
+        let mut world = hecs::World::new();
+        let old_parent = spawn_chain(&mut world, &[translation(0.)]);
+        let new_parent = spawn_chain(&mut world, &[translation(90.)]);
+        let object = spawn_chain(&mut world, &[translation(1.)]);
+        world.add_child(old_parent, object).unwrap();
+        snapshot_transforms(&mut world);
+        world
+            .get_mut::<TransformComponent>(new_parent)
+            .unwrap()
+            .position
+            .x = 100.;
+        set_parent_keep_world(&mut world, object, Some(new_parent)).unwrap();
+        update_global_transforms(&mut world);
+        let global = world.get::<GlobalTransformComponent>(object).unwrap();
+        for &alpha in &[0., 0.5, 1.] {
+            assert_relative_eq!(global.interpolated(alpha)[(0, 3)], 1., epsilon = 1e-4);
+        }
     }
 }
@@ -0,0 +1,138 @@
+use proc_macro::TokenStream;
+use proc_macro2::Span;
+use quote::quote;
+use syn::{parse_macro_input, Data, DeriveInput, Fields, Lit, Meta, NestedMeta};
+
+/// Implements `portal_engine::renderer::Vertex` for a `#[repr(C)]` struct with named fields
+///
+/// Every field becomes an attribute with shader locations assigned in declaration order,
+/// `#[vertex(location = N)]` sets the location of a field (the following ones continue from it)
+/// and `#[vertex(skip)]` ignores padding fields
+#[proc_macro_derive(Vertex, attributes(vertex))]
+pub fn derive_vertex(input: TokenStream) -> TokenStream {
+    let input = parse_macro_input!(input as DeriveInput);
+    expand_vertex(&input)
+        .unwrap_or_else(|error| error.to_compile_error())
+        .into()
+}
+
+fn expand_vertex(input: &DeriveInput) -> syn::Result<proc_macro2::TokenStream> {
+    let name = &input.ident;
+    if !input.generics.params.is_empty() {
+        return Err(syn::Error::new_spanned(
+            &input.generics,
+            "Vertex can't be derived for generic structs",
+        ));
+    }
+    if !has_repr_c(input) {
+        return Err(syn::Error::new(
+            Span::call_site(),
+            "Vertex can only be derived for #[repr(C)] structs",
+        ));
+    }
+    let fields = match &input.data {
+        Data::Struct(data) => match &data.fields {
+            Fields::Named(fields) => &fields.named,
+            _ => {
+                return Err(syn::Error::new_spanned(
+                    &data.fields,
+                    "Vertex can only be derived for structs with named fields",
+                ))
+            }
+        },
+        _ => {
+            return Err(syn::Error::new(
+                Span::call_site(),
+                "Vertex can only be derived for structs",
+            ))
+        }
+    };
+
+    let mut location = 0u32;
+    let mut attributes = Vec::new();
+    for field in fields {
+        let options = FieldOptions::parse(&field.attrs)?;
+        if options.skip {
+            continue;
+        }
+        if let Some(l) = options.location {
+            location = l;
+        }
+        let field_name = field.ident.as_ref().unwrap();
+        let ty = &field.ty;
+        attributes.push(quote! {
+            ::portal_engine::renderer::VertexLayout::attribute::<#ty>(
+                ::portal_engine::memoffset::offset_of!(#name, #field_name) as u64,
+                #location,
+            )
+        });
+        location += 1;
+    }
+
+    Ok(quote! {
+        impl ::portal_engine::renderer::Vertex for #name {
+            fn vertex_layout() -> ::portal_engine::renderer::VertexLayout {
+                ::portal_engine::renderer::VertexLayout::interleaved(
+                    ::std::mem::size_of::<#name>() as u64,
+                    &[#(#attributes),*],
+                )
+            }
+        }
+    })
+}
+
+fn has_repr_c(input: &DeriveInput) -> bool {
+    input
+        .attrs
+        .iter()
+        .filter(|attr| attr.path.is_ident("repr"))
+        .filter_map(|attr| attr.parse_meta().ok())
+        .any(|meta| match meta {
+            Meta::List(list) => list.nested.iter().any(
+                |nested| matches!(nested, NestedMeta::Meta(Meta::Path(path)) if path.is_ident("C")),
+            ),
+            _ => false,
+        })
+}
+
+#[derive(Default)]
+struct FieldOptions {
+    location: Option<u32>,
+    skip: bool,
+}
+impl FieldOptions {
+    fn parse(attrs: &[syn::Attribute]) -> syn::Result<Self> {
+        let mut options = Self::default();
+        for attr in attrs.iter().filter(|attr| attr.path.is_ident("vertex")) {
+            let list = match attr.parse_meta()? {
+                Meta::List(list) => list,
+                meta => return Err(syn::Error::new_spanned(meta, "Expected #[vertex(...)]")),
+            };
+            for nested in list.nested {
+                match nested {
+                    NestedMeta::Meta(Meta::Path(path)) if path.is_ident("skip") => {
+                        options.skip = true
+                    }
+                    NestedMeta::Meta(Meta::NameValue(value)) if value.path.is_ident("location") => {
+                        match &value.lit {
+                            Lit::Int(int) => options.location = Some(int.base10_parse()?),
+                            lit => {
+                                return Err(syn::Error::new_spanned(
+                                    lit,
+                                    "Expected an integer location",
+                                ))
+                            }
+                        }
+                    }
+                    nested => {
+                        return Err(syn::Error::new_spanned(
+                            nested,
+                            "Unknown vertex attribute option, expected `location = N` or `skip`",
+                        ))
+                    }
+                }
+            }
+        }
+        Ok(options)
+    }
+}
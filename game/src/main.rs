@@ -1,9 +1,7 @@
 use std::{
-    borrow::Cow,
     collections::VecDeque,
-    convert::TryInto,
     f32,
-    path::PathBuf,
+    path::{Path, PathBuf},
     str::FromStr,
     time::Instant,
 };
@@ -12,15 +10,42 @@ use imgui::im_str;
 use nalgebra::UnitQuaternion;
 use portal_engine::{
     camera::{CameraComponent, PerspectiveCameraMatrix},
-    hecs_extension::{ChildrenComponent, ParentComponent},
-    renderer::{MeshComponent, Renderer, Texture},
+    hecs_extension::{debug_repair_hierarchy, ChildrenComponent, ParentComponent},
+    renderer::{MeshComponent, MeshUsage, Renderer, RendererConfig, Vertex},
     resource_manager::ResourceManager,
-    transform::TransformComponent,
+    time::FixedTimestep,
+    transform::{snapshot_transforms, update_global_transforms, TransformComponent},
 };
 use rayon::prelude::*;
-use wgpu::util::DeviceExt;
 use winit::dpi::LogicalSize;
 
+#[repr(C)]
+#[derive(Clone, Copy, bytemuck::Zeroable, bytemuck::Pod, Vertex)]
+struct ModelVertex {
+    position: [f32; 3],
+    normal: [f32; 3],
+    uv: [f32; 2],
+}
+
+/// Normals and texture coordinates are zero when the model doesn't have them
+fn model_vertices(mesh: &tobj::Mesh) -> impl Iterator<Item = ModelVertex> + '_ {
+    (0..mesh.positions.len() / 3).map(move |i| ModelVertex {
+        position: [
+            mesh.positions[i * 3],
+            mesh.positions[i * 3 + 1],
+            mesh.positions[i * 3 + 2],
+        ],
+        normal: mesh
+            .normals
+            .get(i * 3..i * 3 + 3)
+            .map_or([0.; 3], |n| [n[0], n[1], n[2]]),
+        uv: mesh
+            .texcoords
+            .get(i * 2..i * 2 + 2)
+            .map_or([0.; 2], |uv| [uv[0], uv[1]]),
+    })
+}
+
 fn main() {
     let mut world = hecs::World::new();
     let mut imgui_ctx = imgui::Context::create();
@@ -38,12 +63,19 @@ fn main() {
     );
 
     println!("Creating renderer...");
-    let mut renderer = Box::new(pollster::block_on(Renderer::new(
-        &window,
-        100,
-        100,
-        &mut imgui_ctx,
-    )));
+    let mut renderer = Box::new(
+        pollster::block_on(Renderer::new(
+            &window,
+            100,
+            100,
+            &mut imgui_ctx,
+            RendererConfig {
+                reverse_z: true,
+                ..Default::default()
+            },
+        ))
+        .expect("Failed to create the renderer"),
+    );
     println!("Created renderer");
 
     let resource_manager = ResourceManager::new();
@@ -56,11 +88,11 @@ fn main() {
                 b: 242. / 255.,
                 a: 1.,
             }),
-            matrix: Box::new({
-                let mut m = PerspectiveCameraMatrix::new();
-                m.0.set_znear_and_zfar(0.1, 2000.);
-                m
-            }),
+            matrix: Box::new(PerspectiveCameraMatrix::reversed_infinite(
+                1.,
+                60.0f32.to_radians(),
+                0.1,
+            )),
             is_enabled: true,
         },
         {
@@ -70,86 +102,16 @@ fn main() {
         },
     ));
 
-    let uniform_buffer = renderer
-        .device
-        .create_buffer_init(&wgpu::util::BufferInitDescriptor {
-            label: None,
-            contents: bytemuck::bytes_of(&[0.0f32, 1., 0., 1.]),
-            usage: wgpu::BufferUsage::UNIFORM,
-        });
-
-    let shader_m = wgpu::ShaderModuleDescriptor {
-        label: None,
-        source: wgpu::ShaderSource::Wgsl(Cow::Borrowed(include_str!("shader.wgsl"))),
-        flags: Default::default(),
-    };
-    let shader = renderer.create_shader(
-        &shader_m,
-        &shader_m,
-        &[&wgpu::BindGroupLayoutDescriptor {
-            label: None,
-            entries: &[
-                wgpu::BindGroupLayoutEntry {
-                    binding: 0,
-                    visibility: wgpu::ShaderStage::FRAGMENT,
-                    ty: wgpu::BindingType::Buffer {
-                        ty: wgpu::BufferBindingType::Uniform,
-                        has_dynamic_offset: false,
-                        min_binding_size: None,
-                    },
-                    count: None,
-                },
-                wgpu::BindGroupLayoutEntry {
-                    binding: 1,
-                    visibility: wgpu::ShaderStage::FRAGMENT,
-                    ty: wgpu::BindingType::Texture {
-                        sample_type: wgpu::TextureSampleType::Float { filterable: true },
-                        view_dimension: wgpu::TextureViewDimension::D2,
-                        multisampled: false,
-                    },
-                    count: None,
-                },
-                wgpu::BindGroupLayoutEntry {
-                    binding: 2,
-                    visibility: wgpu::ShaderStage::FRAGMENT,
-                    ty: wgpu::BindingType::Sampler {
-                        filtering: true,
-                        comparison: false,
-                    },
-                    count: None,
-                },
-            ],
-        }],
-        &[
-            wgpu::VertexBufferLayout {
-                array_stride: 3 * std::mem::size_of::<f32>() as u64,
-                step_mode: wgpu::InputStepMode::Vertex,
-                attributes: &[wgpu::VertexAttribute {
-                    format: wgpu::VertexFormat::Float32x3,
-                    offset: 0,
-                    shader_location: 0,
-                }],
-            },
-            wgpu::VertexBufferLayout {
-                array_stride: 3 * std::mem::size_of::<f32>() as u64,
-                step_mode: wgpu::InputStepMode::Vertex,
-                attributes: &[wgpu::VertexAttribute {
-                    format: wgpu::VertexFormat::Float32x3,
-                    offset: 0,
-                    shader_location: 1,
-                }],
-            },
-            wgpu::VertexBufferLayout {
-                array_stride: 2 * std::mem::size_of::<f32>() as u64,
-                step_mode: wgpu::InputStepMode::Vertex,
-                attributes: &[wgpu::VertexAttribute {
-                    format: wgpu::VertexFormat::Float32x2,
-                    offset: 0,
-                    shader_location: 2,
-                }],
-            },
-        ],
-    );
+    let shader = renderer
+        .create_shader_from_file(
+            Path::new("game/src/shader.wgsl"),
+            Some(&[ModelVertex::vertex_layout()]),
+        )
+        .unwrap();
+    // Textured materials may contain cut-out parts like leaves
+    let alpha_tested_shader = renderer
+        .get_shader_permutation(shader, &["ALPHA_TEST"])
+        .unwrap();
 
     let (models, materials) = tobj::load_obj(
         "resources/crytek-sponza-huge-vray-obj/crytek-sponza-huge-vray.obj",
@@ -171,59 +133,46 @@ fn main() {
     let material_refs = materials
         .par_iter()
         .map(|material| {
-            let mut texture = if material.diffuse_texture != "" {
-                resource_manager
+            let (texture, material_shader, color) = if material.diffuse_texture != "" {
+                let mut texture = resource_manager
                     .load_texture_from_file(
                         &renderer,
                         &PathBuf::from_str("resources/crytek-sponza-huge-vray-obj")
                             .unwrap()
                             .join(&material.diffuse_texture),
                     )
-                    .unwrap()
+                    .unwrap();
+                texture.create_sampler(
+                    &renderer,
+                    wgpu::AddressMode::Repeat,
+                    wgpu::FilterMode::Linear,
+                    wgpu::FilterMode::Linear,
+                );
+                (renderer.add_texture(texture), alpha_tested_shader, [1.; 4])
             }
             else {
-                Texture::create_plain_color_texture(
-                    &renderer,
-                    image::Rgba::<u8>(
-                        material
-                            .diffuse
-                            .iter()
-                            .map(|a| (a * 255.) as u8)
-                            .chain(std::iter::once(255u8))
-                            .collect::<Vec<_>>()
-                            .try_into()
-                            .unwrap(),
-                    ),
-                    Some(&material.name),
-                )
+                // The diffuse color is in sRGB while the shader works in linear space
+                let [r, g, b] = material.diffuse;
+                (renderer.default_texture(), shader, [
+                    r.powf(2.2),
+                    g.powf(2.2),
+                    b.powf(2.2),
+                    1.,
+                ])
             };
-            texture.create_sampler(
-                &renderer,
-                wgpu::AddressMode::Repeat,
-                wgpu::FilterMode::Linear,
-                wgpu::FilterMode::Linear,
-            );
 
-            renderer.create_material(
-                shader,
-                &[&[
-                    wgpu::BindGroupEntry {
-                        binding: 0,
-                        resource: wgpu::BindingResource::Buffer(
-                            uniform_buffer.as_entire_buffer_binding(),
-                        ),
-                    },
-                    wgpu::BindGroupEntry {
-                        binding: 1,
-                        resource: wgpu::BindingResource::TextureView(&texture.view),
-                    },
-                    wgpu::BindGroupEntry {
-                        binding: 2,
-                        resource: wgpu::BindingResource::Sampler(texture.sampler.as_ref().unwrap()),
-                    },
-                ]],
-                Some(wgpu::Face::Front),
-            )
+            let material_ref = renderer
+                .create_material(material_shader, Some(wgpu::Face::Front))
+                .unwrap();
+            renderer
+                .edit_material(material_ref, |m| -> anyhow::Result<()> {
+                    m.set_vec4("color", color)?;
+                    m.set_texture("diffuse_texture", texture)?;
+                    m.set_sampler("diffuse_sampler", texture)
+                })
+                .and_then(|result| result)
+                .unwrap();
+            material_ref
         })
         .collect::<Vec<_>>();
 
@@ -233,19 +182,22 @@ fn main() {
             models
                 .par_iter()
                 .map(|model| {
-                    renderer.create_mesh(
-                        material_refs[model.mesh.material_id.unwrap()],
-                        &model.mesh.indices,
-                        &[
-                            &model.mesh.positions,
-                            &model.mesh.normals,
-                            &model.mesh.texcoords,
-                        ],
-                    )
+                    let mesh = &model.mesh;
+                    let vertices = model_vertices(mesh).collect::<Vec<_>>();
+                    renderer
+                        .create_mesh_from_vertices(
+                            &[material_refs[mesh.material_id.unwrap()]],
+                            &[],
+                            MeshUsage::Static,
+                            wgpu::PrimitiveTopology::TriangleList,
+                            mesh.indices.as_slice(),
+                            &vertices,
+                        )
+                        .unwrap()
                 })
                 .map(|mesh| {
                     (
-                        MeshComponent(mesh),
+                        MeshComponent::new(mesh),
                         TransformComponent::default(),
                         ParentComponent(Some(parent)),
                     )
@@ -266,10 +218,12 @@ fn main() {
         ),
     );
 
-    let start = Instant::now();
+    let mut timestep = FixedTimestep::from_rate(60);
+    let mut last_update = Instant::now();
     let mut last_frame = Instant::now();
     let mut frames = VecDeque::new();
     let mut last_frame_time = Instant::now();
+    let mut trace_status = String::new();
     event_loop.run(move |event, _, control_flow| {
         use winit::{
             event::{Event, WindowEvent},
@@ -294,15 +248,13 @@ fn main() {
                 ..
             } => {
                 imgui_platform.handle_event(imgui_ctx.io_mut(), &window, &event);
-                renderer.resize(size.width, size.height);
-
-                let mut camera_component = world.get_mut::<CameraComponent>(camera_entity).unwrap();
-                let matrix: &mut Box<PerspectiveCameraMatrix> =
-                    unsafe { std::mem::transmute(&mut camera_component.matrix) };
-                matrix.0.set_aspect(size.width as f32 / size.height as f32);
+                renderer.resize(size.width, size.height, &world);
             }
 
             Event::MainEventsCleared => {
+                for error in renderer.reload_modified_shaders() {
+                    eprintln!("{:?}", error);
+                }
                 imgui_platform
                     .prepare_frame(imgui_ctx.io_mut(), &window)
                     .expect("Failed to prepare frame");
@@ -312,12 +264,23 @@ fn main() {
                 let ui = imgui_ctx.frame();
 
                 imgui::Window::new(im_str!("Performances"))
-                    .size([300.0, 110.0], imgui::Condition::FirstUseEver)
+                    .size([300.0, 280.0], imgui::Condition::FirstUseEver)
                     .build(&ui, || {
                         ui.text(format!("FPS: {}", ui.io().framerate));
+                        let stats = renderer.render_stats();
+                        ui.text(format!("Meshes drawn: {}", stats.drawn_meshes));
+                        ui.text(format!(
+                            "Draw calls: {}, triangles: {}",
+                            stats.draw_calls, stats.triangles
+                        ));
                         ui.text(format!(
-                            "Meshes: {}",
-                            world.query_mut::<&MeshComponent>().into_iter().count()
+                            "Pipeline changes: {}, bind group changes: {}",
+                            stats.pipeline_changes, stats.bind_group_changes
+                        ));
+                        ui.text(format!(
+                            "Memory: {:.1}MiB buffers, {:.1}MiB textures",
+                            stats.buffer_memory as f64 / (1024. * 1024.),
+                            stats.texture_memory as f64 / (1024. * 1024.)
                         ));
                         imgui::PlotHistogram::new(
                             &ui,
@@ -329,20 +292,48 @@ fn main() {
                         let mut is_vsync_enabled = renderer.get_vsync();
                         ui.checkbox(im_str!("Enable VSYNC"), &mut is_vsync_enabled);
                         renderer.set_vsync(is_vsync_enabled);
+                        ui.separator();
+                        let profiler = renderer.profiler();
+                        for scope in profiler.stats() {
+                            ui.text(format!(
+                                "{}: CPU {:.2}ms (max {:.2}ms){}",
+                                scope.name,
+                                scope.cpu.average,
+                                scope.cpu.max,
+                                scope.gpu.map_or(String::new(), |gpu| format!(
+                                    ", GPU {:.2}ms (max {:.2}ms)",
+                                    gpu.average, gpu.max
+                                ))
+                            ));
+                        }
+                        if ui.button(im_str!("Export trace"), [0., 0.]) {
+                            let result =
+                                std::fs::File::create("frame_trace.json").and_then(|file| {
+                                    profiler.write_chrome_trace(std::io::BufWriter::new(file))
+                                });
+                            trace_status = match result {
+                                Ok(()) => "Wrote frame_trace.json".to_string(),
+                                Err(error) => format!("Failed to write the trace: {}", error),
+                            };
+                        }
+                        ui.text(&trace_status);
                     });
 
-                {
+                let now = Instant::now();
+                let ticks = timestep.advance(now - last_update);
+                last_update = now;
+                for tick in timestep.ticks() - ticks as u64 + 1..=timestep.ticks() {
+                    snapshot_transforms(&mut world);
+                    let time = tick as f32 * timestep.step_secs();
                     let mut t = world.get_mut::<TransformComponent>(camera_entity).unwrap();
-                    t.rotation = UnitQuaternion::from_euler_angles(
-                        0.,
-                        start.elapsed().as_secs_f32() / 5.,
-                        0.,
-                    );
-                    t.position.x = (start.elapsed().as_secs_f32() / 5.).cos() * 1000.;
+                    t.rotation = UnitQuaternion::from_euler_angles(0., time / 5., 0.);
+                    t.position.x = (time / 5.).cos() * 1000.;
                 }
 
+                debug_repair_hierarchy(&mut world);
+                update_global_transforms(&mut world);
                 imgui_platform.prepare_render(&ui, &window);
-                renderer.render(ui.render(), &world);
+                renderer.render(ui.render(), &world, timestep.alpha());
             }
 
             Event::WindowEvent {
@@ -1,9 +1,5 @@
-[[block]] struct RenderUniforms {
-    view_projection: mat4x4<f32>;
-    model_matrix: mat4x4<f32>;
-};
-[[group(0), binding(0)]]
-var<uniform> render_uniforms: RenderUniforms;
+#include "transforms"
+#include "lighting"
 
 struct VertexOutputs {
     [[builtin(position)]] position: vec4<f32>;
@@ -18,7 +14,7 @@ fn vertex(
     [[location(2)]] uv: vec2<f32>,
 ) -> VertexOutputs {
     return VertexOutputs(
-        render_uniforms.view_projection * render_uniforms.model_matrix * vec4<f32>(position, 1.0),
+        model_to_clip(position),
         normal,
         uv
     );
@@ -30,16 +26,20 @@ fn vertex(
 [[group(1), binding(0)]]
 var<uniform> uniforms: Uniforms;
 [[group(1), binding(1)]]
-var u_diffuse_texture: texture_2d<f32>;
+var diffuse_texture: texture_2d<f32>;
 [[group(1), binding(2)]]
-var u_diffuse_sampler: sampler;
+var diffuse_sampler: sampler;
 
 [[stage(fragment)]]
 fn fragment(vertex_outputs: VertexOutputs) -> [[location(0)]] vec4<f32> {
-    let diffuse = textureSample(u_diffuse_texture, u_diffuse_sampler, vec2<f32>(vertex_outputs.uv.x, 1. - vertex_outputs.uv.y));
-    let directional_light_factor = (dot(vertex_outputs.normal, vec3<f32>(1., 0., 0.)) + 1.) / 2.;
+    let diffuse = textureSample(diffuse_texture, diffuse_sampler, vec2<f32>(vertex_outputs.uv.x, 1. - vertex_outputs.uv.y)) * uniforms.color;
+#ifdef ALPHA_TEST
+    if (diffuse.a < 0.5) {
+        discard;
+    }
+#endif
 
-    let light_factor = max(0.25, directional_light_factor);
+    let light_factor = max(0.25, half_lambert(vertex_outputs.normal, vec3<f32>(1., 0., 0.)));
 
     return diffuse * light_factor;
 }